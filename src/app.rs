@@ -1,18 +1,52 @@
-use crate::arduino::Arduino;
-use crate::arduino::PacketData;
+use crate::arduino::ConnectError;
 use crate::arduino::ThreadMSG;
+use crate::arduino::{PacketData, PacketKind};
+use crate::capture;
+use crate::clock::{unix_secs, ClockSync, SampleTime};
+use crate::connection::DeviceMSG;
 use crate::data_window;
 use crate::data_window::DataWindow;
+use crate::device::{ChannelDescriptor, ChannelMetadata, DeviceDescriptor};
 use crate::error_message;
+use crate::export::{self, channel_label, ExportOptions};
+use crate::import::{self, ColumnRole, ImportOptions, ImportedChannel};
+use crate::ports::{self, PortEvent, PortIdentity};
+use crate::replay::{ReplayCommand, ReplayStatus};
+use crate::serial_settings::SerialSettings;
+use crate::store::{format_bytes, ChannelKey, ChannelStore, Retention, TypeChange};
+use crate::terminal::Terminal;
 use colored::Colorize;
-use egui::vec2;
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::usize;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio_serial::SerialPortInfo;
+
+/// Speeds offered for replaying a capture
+const REPLAY_SPEEDS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 50.0];
+
+/// How long the new device notification stays up
+const NOTIFICATION_TIME: Duration = Duration::from_secs(10);
+
+/// Everything known about one connection. Devices are named after the port they were first
+/// opened on, the name stays the same if the board is renamed when reconnecting.
+#[derive(Default)]
+struct DeviceState {
+    port_path: String, // Where the board is now
+    connected: bool,
+    settings: SerialSettings, // What the port was last opened with
+    descriptor: Option<DeviceDescriptor>,
+    channels: HashMap<u8, ChannelDescriptor>,
+    metadata: HashMap<u8, ChannelMetadata>,
+    reconnect_attempt: Option<u32>, // Set while the connection is lost
+    clock_sync: ClockSync,
+}
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -21,38 +55,95 @@ pub struct TemplateApp {
     // Example stuff:
     label: String,
     #[serde(skip)]
-    pub data_collection: Arc<Mutex<Vec<Vec<PacketData>>>>,
+    pub data_collection: Arc<Mutex<BTreeMap<ChannelKey, ChannelStore>>>,
     #[serde(skip)] // This how you opt-out of serialization of a field
     value: f32,
     #[serde(skip)]
-    selected_port: String,
-    #[serde(skip)]
     tx: mpsc::Sender<ThreadMSG>,
     #[serde(skip)]
-    rx: mpsc::Receiver<ThreadMSG>,
-    #[serde(skip)]
-    arduino: Arc<Mutex<Arduino>>,
+    rx: mpsc::Receiver<DeviceMSG>,
     #[serde(skip)]
     windows: Vec<DataWindow>,
     #[serde(skip)]
-    window_status: HashMap<String, bool>,
+    window_status: HashMap<String, bool>, // Keyed by DataWindow::key
+    #[serde(skip)]
+    devices: BTreeMap<String, DeviceState>,
+    port_settings: HashMap<String, SerialSettings>, // Last settings used for each port
+    port_aliases: HashMap<String, String>,          // Friendly names keyed by the board's identity
+    last_identity: Option<PortIdentity>,            // The board connected to last
+    retention: Retention,                           // How much of each channel is kept
+    capture_dir: String,                            // Where recordings are saved
+    replay_path: String,                            // Capture file to replay
+    export_options: ExportOptions,
+    import_options: ImportOptions,
+    #[serde(skip)]
+    recording: Option<PathBuf>, // Capture file being written
+    #[serde(skip)]
+    import_dialog: Option<ImportDialog>,
+    #[serde(skip)]
+    terminals: BTreeMap<String, Terminal>, // Raw bytes from each device
+    #[serde(skip)]
+    export_dialog: Option<ExportDialog>,
+    #[serde(skip)]
+    replay: Option<ReplayStatus>, // Where the replay has got to, if one is open
+    #[serde(skip)]
+    replay_id: u64, // The replay started last, statuses from older ones are ignored
+    #[serde(skip)]
+    replay_seek: Option<f64>, // Position being dragged to, in seconds
+    #[serde(skip)]
+    auto_selected: bool, // The last board has already been offered this session
+    #[serde(skip)]
+    connect_dialog: Option<(String, SerialSettings)>, // Port being connected to
+    #[serde(skip)]
+    errors: Vec<error_message::ErrorInfo>, // Errors the user hasn't dismissed yet
+    #[serde(skip)]
+    ports: Vec<SerialPortInfo>, // Kept up to date by the port watcher
+    #[serde(skip)]
+    port_events: mpsc::Receiver<Vec<PortEvent>>,
+    #[serde(skip)]
+    ports_listed: bool, // The watcher's first batch has arrived
+    #[serde(skip)]
+    notifications: Vec<(SerialPortInfo, Instant)>, // Newly attached ports & when they appeared
 }
 
 impl Default for TemplateApp {
     fn default() -> Self {
-        let (tx, rx) = mpsc::channel(10);
+        let (tx, _) = mpsc::channel(10);
+        let (_, rx) = mpsc::channel(10);
+        let (_, port_events) = mpsc::channel(1);
 
         Self {
             // Example stuff:
             label: "Hello World!".to_owned(),
             value: 2.7,
-            selected_port: "Disconnected".to_owned(),
             tx,
             rx,
-            arduino: Arc::new(Mutex::new(Arduino::new())),
-            data_collection: Arc::new(Mutex::new(Vec::new())),
+            data_collection: Arc::new(Mutex::new(BTreeMap::new())),
             windows: Vec::new(),
             window_status: HashMap::new(),
+            devices: BTreeMap::new(),
+            port_settings: HashMap::new(),
+            port_aliases: HashMap::new(),
+            last_identity: None,
+            retention: Retention::default(),
+            capture_dir: "captures".to_owned(),
+            recording: None,
+            replay_path: String::new(),
+            export_options: ExportOptions::default(),
+            import_options: ImportOptions::default(),
+            import_dialog: None,
+            terminals: BTreeMap::new(),
+            export_dialog: None,
+            replay: None,
+            replay_id: 0,
+            replay_seek: None,
+            auto_selected: false,
+            connect_dialog: None,
+            errors: Vec::new(),
+            ports: Vec::new(),
+            port_events,
+            ports_listed: false,
+            notifications: Vec::new(),
         }
     }
 }
@@ -62,25 +153,34 @@ impl Default for TemplateApp {
 impl TemplateApp {
     /// Called once before the first frame.
     pub fn new(
-        _cc: &eframe::CreationContext<'_>,
-        rx: mpsc::Receiver<ThreadMSG>,
+        cc: &eframe::CreationContext<'_>,
+        rx: mpsc::Receiver<DeviceMSG>,
         tx: mpsc::Sender<ThreadMSG>,
-        arduino: Arc<Mutex<Arduino>>,
-        _data_collection: Arc<Mutex<Vec<Vec<usize>>>>,
+        port_events: mpsc::Receiver<Vec<PortEvent>>,
     ) -> Self {
+        // Only the persisted fields are restored, the rest start fresh
+        let saved: Self = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
         Self {
-            arduino,
             rx,
             tx,
+            port_events,
+            label: saved.label,
+            port_settings: saved.port_settings,
+            port_aliases: saved.port_aliases,
+            last_identity: saved.last_identity,
+            retention: saved.retention,
+            capture_dir: saved.capture_dir,
+            replay_path: saved.replay_path,
+            export_options: saved.export_options,
+            import_options: saved.import_options,
             ..Default::default()
         }
     }
 }
 
-fn print_type_of<T>(_: &T) {
-    println!("{}", std::any::type_name::<T>())
-}
-
 impl eframe::App for TemplateApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
@@ -89,7 +189,11 @@ impl eframe::App for TemplateApp {
 
     /// Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        ctx.request_repaint();
+        self.receive_messages(ctx);
+        // Nothing may arrive to wake the UI, so wake it when the oldest sample needs dropping
+        if let Some(wait) = self.trim_channels(Instant::now()) {
+            ctx.request_repaint_after(wait);
+        }
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
         let _t = self.tx.clone();
@@ -108,7 +212,11 @@ impl eframe::App for TemplateApp {
                     });
                 }
                 show_port_menu(self, ui);
-                show_data_menu(self, ctx, ui);
+                show_data_menu(self, ui);
+                show_storage_menu(self, ui);
+                show_record_menu(self, ui);
+                show_replay_menu(self, ui);
+                show_terminal_menu(self, ui);
             });
         });
 
@@ -126,6 +234,7 @@ impl eframe::App for TemplateApp {
             ui.separator();
             ui.heading("Available Serial Devices:");
             show_available_ports(self, ui);
+            show_device_status(self, ui);
             ui.separator();
             ui.add(egui::github_link_file!(
                 "https://github.com/Portablefire22/Arduino-Communication-GUI/blob/master/",
@@ -137,44 +246,329 @@ impl eframe::App for TemplateApp {
             });
         });
 
+        self.update_ports();
         show_windows(self, ctx);
-        match self.rx.try_recv() {
-            Err(TryRecvError::Disconnected) => {
-                let mut err_win = error_message::ErrorInfo::new(
-                    "Receiver Disconnected!".to_owned(),
-                    "Receiver has disconnected, the Arduino thread has likely panicked!".to_owned(),
-                    error_message::ErrorSeverity::Critical,
-                );
-                err_win.show(&ctx);
-            } // TODO, error message as pop-up
-            Err(_) => (),
-            Ok(t) => match t {
-                ThreadMSG::Data(data) => match data {
-                    PacketData::String(_, id, _time)
-                    | PacketData::Integer(_, id, _time)
-                    | PacketData::Float(_, id, _time) => match self.data_collection.lock() {
-                        Ok(mut t) => match t.get(id as usize) {
-                            None => {
-                                if id == 0 {
-                                    t.resize(1, Vec::new());
-                                } else {
-                                    t.resize(id as usize + 1, Vec::new());
-                                }
-                                t[id as usize].push(data);
-                            }
-                            Some(_) => {
-                                t[id as usize].push(data);
-                            }
-                        },
-                        Err(_) => {
-                            eprintln!("Mutex error: Error unlocking whilst retrieving data")
+        show_connect_dialog(self, ctx);
+        show_replay_window(self, ctx);
+        show_export_dialog(self, ctx);
+        show_import_dialog(self, ctx);
+        for terminal in self.terminals.values_mut().filter(|t| t.open) {
+            terminal.show(ctx);
+        }
+        show_notifications(self, ctx);
+        self.errors.retain_mut(|error| error.show(ctx));
+    }
+}
+
+impl TemplateApp {
+    /// Handles every message waiting from the connections, the connection manager wakes the UI
+    /// whenever it sends one so nothing waits for the next frame
+    fn receive_messages(&mut self, ctx: &egui::Context) {
+        loop {
+            match self.rx.try_recv() {
+                Err(TryRecvError::Disconnected) => {
+                    let mut err_win = error_message::ErrorInfo::new(
+                        "Receiver Disconnected!".to_owned(),
+                        "Receiver has disconnected, the Arduino thread has likely panicked!"
+                            .to_owned(),
+                        error_message::ErrorSeverity::Critical,
+                    );
+                    err_win.show(ctx);
+                    break;
+                } // TODO, error message as pop-up
+                Err(TryRecvError::Empty) => break,
+                Ok((device, msg)) => self.handle_msg(device, msg),
+            }
+        }
+    }
+
+    /// Handles a message sent by one of the connections
+    fn handle_msg(&mut self, device: String, msg: ThreadMSG) {
+        match msg {
+            ThreadMSG::ConnectFailed((port, e)) => self.connect_failed(&device, port, e),
+            ThreadMSG::ConnectionLost(_) => self.connection_lost(&device),
+            ThreadMSG::Reconnecting((_, attempt)) => {
+                self.device_mut(&device).reconnect_attempt = Some(attempt)
+            }
+            ThreadMSG::Reconnected(port) => {
+                let state = self.device_mut(&device);
+                state.reconnect_attempt = None;
+                state.port_path = port;
+            }
+            ThreadMSG::Data(PacketData::Descriptor(descriptor)) => {
+                self.device_mut(&device).descriptor = Some(descriptor)
+            }
+            ThreadMSG::Data(PacketData::Channel(channel)) => self.add_channel(&device, channel),
+            ThreadMSG::Data(PacketData::Metadata(metadata)) => self.set_metadata(&device, metadata),
+            ThreadMSG::RecordingFailed(e) => {
+                self.recording = None;
+                self.errors.push(error_message::ErrorInfo::new(
+                    "Recording stopped".to_owned(),
+                    e,
+                    error_message::ErrorSeverity::Minimal,
+                ));
+            }
+            ThreadMSG::Raw((bytes, time)) => self
+                .terminals
+                .entry(device.clone())
+                .or_insert_with(|| Terminal::new(device))
+                .push(time, bytes),
+            ThreadMSG::ReplayStatus(status) if status.id == self.replay_id => {
+                self.replay = (!status.stopped).then_some(status);
+            }
+            ThreadMSG::ReplayFailed((id, e)) if id == self.replay_id => {
+                self.replay = None;
+                self.errors.push(error_message::ErrorInfo::new(
+                    "Could not replay capture".to_owned(),
+                    e,
+                    error_message::ErrorSeverity::Minimal,
+                ));
+            }
+            ThreadMSG::Data(data) => {
+                if let Some(time) = data.time() {
+                    self.device_mut(&device).clock_sync.add(time);
+                }
+                self.store(&device, data);
+            }
+            _ => (),
+        }
+    }
+
+    /// The device's state, created if this is the first that has been heard from it
+    fn device_mut(&mut self, device: &str) -> &mut DeviceState {
+        self.devices
+            .entry(device.to_owned())
+            .or_insert_with(|| DeviceState {
+                port_path: device.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    /// The device using the port, if any
+    fn device_on_port(&self, port_name: &str) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|(_, state)| state.connected && state.port_path.eq_ignore_ascii_case(port_name))
+            .map(|(device, _)| device.as_str())
+    }
+
+    /// Opens the port as its own device, the device keeps the data it had from last time
+    fn connect(&mut self, port_name: String, settings: SerialSettings) {
+        let state = self.device_mut(&port_name);
+        state.connected = true;
+        state.reconnect_attempt = None;
+        state.settings = settings.clone();
+        send_thread_msg(self.tx.clone(), ThreadMSG::Start((port_name, settings)));
+    }
+
+    fn disconnect(&mut self, device: &str) {
+        let state = self.device_mut(device);
+        state.connected = false;
+        state.reconnect_attempt = None;
+        state.descriptor = None;
+        send_thread_msg(self.tx.clone(), ThreadMSG::Disconnect(device.to_owned()));
+    }
+
+    /// Applies the changes found by the port watcher
+    fn update_ports(&mut self) {
+        let mut changed = false;
+        while let Ok(events) = self.port_events.try_recv() {
+            changed = true;
+            for event in events {
+                match event {
+                    PortEvent::Added(port) => {
+                        // Ports that were there before the app started aren't new
+                        if self.ports_listed {
+                            self.notifications.push((port.clone(), Instant::now()));
                         }
-                    },
-                    _ => (),
+                        self.ports.push(port);
+                    }
+                    PortEvent::Removed(port) => {
+                        self.ports.retain(|p| p.port_name != port.port_name);
+                        self.notifications
+                            .retain(|(p, _)| p.port_name != port.port_name);
+                    }
+                }
+            }
+            self.ports_listed = true;
+        }
+        if changed {
+            auto_select_port(self);
+        }
+    }
+
+    /// Reports the failed connection and goes back to being disconnected
+    fn connect_failed(&mut self, device: &str, port: String, e: ConnectError) {
+        eprintln!("{} {}: {}", "Could not connect to".red(), port, e);
+        self.device_mut(device).connected = false;
+        self.errors.push(error_message::ErrorInfo::new(
+            format!("Could not connect to {}", port),
+            e.to_string(),
+            error_message::ErrorSeverity::Minimal,
+        ));
+    }
+
+    /// Marks the gap in every channel so lines aren't drawn across it
+    fn connection_lost(&mut self, device: &str) {
+        let state = self.device_mut(device);
+        state.reconnect_attempt = Some(0);
+        state.descriptor = None; // The hello is sent again on reconnecting
+        let time = SampleTime::now();
+        match self.data_collection.lock() {
+            Ok(mut data) => {
+                for ((_, id), channel) in data.iter_mut().filter(|((d, _), _)| d == device) {
+                    if !channel.is_empty() {
+                        channel.push(PacketData::Gap(*id, time), &self.retention);
+                    }
+                }
+            }
+            Err(_) => eprintln!("Mutex error: Error unlocking whilst marking a gap"),
+        }
+    }
+
+    /// Creates a window for a channel the device announced, or updates the window if it already
+    /// has one
+    fn add_channel(&mut self, device: &str, channel: ChannelDescriptor) {
+        let id = channel.id as usize;
+        match self
+            .windows
+            .iter_mut()
+            .find(|w| w.device == device && w.selected_data == id)
+        {
+            Some(window) => window.set_channel(&channel),
+            None => {
+                let window = DataWindow::for_channel(device.to_owned(), &channel);
+                self.window_status.insert(window.key(), true);
+                self.windows.push(window);
+            }
+        }
+        // Metadata sent on its own is more specific than the channel table
+        let state = self.device_mut(device);
+        if let Some(metadata) = state.metadata.get(&channel.id).cloned() {
+            self.set_metadata(device, metadata);
+        } else {
+            state
+                .metadata
+                .insert(channel.id, ChannelMetadata::from(&channel));
+        }
+        self.device_mut(device).channels.insert(channel.id, channel);
+    }
+
+    /// Names the channel and sets its unit & scaling, the window picks it up now or when created
+    fn set_metadata(&mut self, device: &str, metadata: ChannelMetadata) {
+        let id = metadata.id as usize;
+        if let Some(window) = self
+            .windows
+            .iter_mut()
+            .find(|w| w.device == device && w.selected_data == id)
+        {
+            window.set_metadata(&metadata);
+        }
+        self.device_mut(device)
+            .metadata
+            .insert(metadata.id, metadata);
+    }
+
+    /// Adds the imported channels as a device of their own, replacing anything imported from
+    /// the same file before. Each channel gets a window like an announced channel would.
+    fn add_import(&mut self, device: &str, channels: Vec<ImportedChannel>) {
+        match self.data_collection.lock() {
+            Ok(mut data) => {
+                data.retain(|(d, _), _| d != device);
+                for channel in &channels {
+                    data.insert(
+                        (device.to_owned(), channel.id),
+                        ChannelStore::imported(channel.samples.clone()),
+                    );
+                }
+            }
+            Err(_) => {
+                eprintln!("Mutex error: Error unlocking whilst importing");
+                return;
+            }
+        }
+        for channel in channels {
+            self.add_channel(
+                device,
+                ChannelDescriptor {
+                    id: channel.id,
+                    name: channel.name,
+                    kind: PacketKind::Float64,
+                    unit: channel.unit,
+                    range: None,
                 },
-                _ => (),
-            },
+            );
+        }
+    }
+
+    /// Drops samples that have got too old even if nothing new has arrived, returns how long
+    /// until the next one is too old
+    fn trim_channels(&mut self, now: Instant) -> Option<Duration> {
+        self.retention.max_age?;
+        match self.data_collection.lock() {
+            Ok(mut data) => data
+                .values_mut()
+                .filter_map(|channel| {
+                    channel.trim(&self.retention, now);
+                    channel.next_expiry(&self.retention, now)
+                })
+                .min(),
+            Err(_) => {
+                eprintln!("Mutex error: Error unlocking whilst trimming data");
+                None
+            }
+        }
+    }
+
+    /// Adds the data to the channel for its device & packet ID
+    fn store(&mut self, device: &str, data: PacketData) {
+        match data {
+            PacketData::String(_, id, _time)
+            | PacketData::Integer(_, id, _time)
+            | PacketData::Float(_, id, _time)
+            | PacketData::Binary(_, id, _time)
+            | PacketData::Vector(_, id, _time) => {
+                let change = match self.data_collection.lock() {
+                    Ok(mut data_collection) => data_collection
+                        .entry((device.to_owned(), id))
+                        .or_default()
+                        .push(data, &self.retention),
+                    Err(_) => {
+                        eprintln!("Mutex error: Error unlocking whilst retrieving data");
+                        None
+                    }
+                };
+                if let Some(change) = change {
+                    self.type_changed(device, id, change);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Warns that a channel's data changed type, the data is still kept
+    fn type_changed(&mut self, device: &str, id: u8, change: TypeChange) {
+        eprintln!(
+            "{} {} ID {} changed from {} to {}",
+            "Type changed:".red(),
+            device,
+            id,
+            change.from,
+            change.to
+        );
+        let title = format!("Channel {} on {} changed type", id, device);
+        // Only one warning for the channel at a time
+        if self.errors.iter().any(|error| error.title() == title) {
+            return;
         }
+        self.errors.push(error_message::ErrorInfo::new(
+            title,
+            format!(
+                "Packet ID {} was {} but is now {}, is the firmware sending the right type?",
+                id, change.from, change.to
+            ),
+            error_message::ErrorSeverity::Minimal,
+        ));
     }
 }
 
@@ -182,143 +576,935 @@ fn show_windows(app: &mut TemplateApp, ctx: &egui::Context) {
     match app.data_collection.lock() {
         Err(_e) => eprintln!("Error locking mutex!"),
         Ok(data) => {
+            // Announced channels get a window before any of their data arrives
+            let empty = ChannelStore::new();
+            let no_sync = ClockSync::new();
             for window in &mut app.windows {
-                let tmp_str = &window.selected_data.to_string();
+                let channel = data.get(&(window.device.clone(), window.selected_data as u8));
+                let clock_sync = match app.devices.get(&window.device) {
+                    Some(state) => &state.clock_sync,
+                    None => &no_sync,
+                };
                 window.show(
                     ctx,
-                    &data[window.selected_data],
-                    app.window_status.get_mut(tmp_str).unwrap(),
+                    channel.unwrap_or(&empty),
+                    app.window_status.entry(window.key()).or_insert(true),
+                    clock_sync,
+                    &data,
                 );
             }
         }
     }
 }
 
-pub fn send_thread_msg(tx: mpsc::Sender<ThreadMSG>, msg: ThreadMSG) {
+pub fn send_thread_msg<T: Debug + Send + 'static>(tx: mpsc::Sender<T>, msg: T) {
     tokio::spawn(async move {
-        match tx.send(msg.clone()).await {
-            Err(t) => eprintln!(
+        if let Err(t) = tx.send(msg).await {
+            eprintln!(
                 "{} '{:?}' {}\n{}!",
                 "Could not send".red(),
-                &msg,
+                &t.0,
                 "to Arduino thread!".red(),
                 t,
-            ),
-            _ => (),
+            )
         };
     });
 }
 
-fn show_data_menu(app: &mut TemplateApp, ctx: &egui::Context, ui: &mut egui::Ui) {
-    match app.data_collection.lock() {
+fn show_data_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    let data_collection = app.data_collection.clone();
+    match data_collection.lock() {
         Err(e) => {
             eprintln!("Attempted to access data whilst mutex was locked!");
             eprintln!("{}", e);
         }
         Ok(data) => {
             ui.menu_button("Data", |ui| {
-                if data.len() == 0 {
+                let devices: BTreeSet<String> = data
+                    .keys()
+                    .map(|(device, _)| device)
+                    .chain(app.devices.keys())
+                    .cloned()
+                    .collect();
+                if devices.is_empty() {
                     ui.label("No data stored!");
-                } else {
-                    for (index_iter, dat) in data.iter().enumerate() {
-                        if ui
-                            .button(format!(
-                                "{} | {}",
-                                index_iter,
-                                match dat.get(index_iter) {
-                                    None => "Unknown!",
-                                    Some(t) => t.display_variant(),
-                                }
-                            ))
-                            .clicked()
-                        {
-                            // Prevents duplicate windows
-                            let mut t: Vec<_> = app
-                                .windows
-                                .iter()
-                                .filter(|w| w.selected_data == index_iter)
-                                .collect();
-                            match t.get_mut(0) {
-                                Some(_) => {
-                                    app.window_status
-                                        .entry(index_iter.to_string())
-                                        .and_modify(|x| *x = !*x);
-                                }
-                                None => {
-                                    let window = data_window::DataWindow::new(
-                                        index_iter.to_string(),
-                                        index_iter,
-                                    );
-                                    app.windows.push(window);
-                                    app.window_status.insert(index_iter.to_string(), true);
-                                }
+                }
+                for device in devices {
+                    ui.menu_button(&device, |ui| {
+                        show_device_data_menu(app, ui, &device, &data);
+                    });
+                }
+                ui.separator();
+                if ui.button("Import CSV...").clicked() {
+                    app.import_dialog = Some(ImportDialog::default());
+                    ui.close_menu();
+                }
+                if ui.button("Export to CSV...").clicked() {
+                    app.export_dialog = Some(ExportDialog {
+                        selected: data.keys().cloned().collect(),
+                        ..Default::default()
+                    });
+                    ui.close_menu();
+                }
+            });
+        }
+    };
+}
+
+/// Lists the packet IDs one device has sent or announced
+fn show_device_data_menu(
+    app: &mut TemplateApp,
+    ui: &mut egui::Ui,
+    device: &str,
+    data: &BTreeMap<ChannelKey, ChannelStore>,
+) {
+    let no_metadata = HashMap::new();
+    let all_metadata = match app.devices.get(device) {
+        Some(state) => &state.metadata,
+        None => &no_metadata,
+    };
+    // Channels the device announced are listed even if nothing has arrived yet
+    let ids: BTreeSet<u8> = data
+        .keys()
+        .filter(|(d, _)| d == device)
+        .map(|(_, id)| *id)
+        .chain(all_metadata.keys().copied())
+        .collect();
+    let mut opened = None;
+    for id in &ids {
+        let kind = data
+            .get(&(device.to_owned(), *id))
+            .and_then(ChannelStore::kind);
+        let metadata = all_metadata.get(id);
+        let name = match metadata {
+            Some(metadata) => format!(" | {}", metadata.name),
+            None => String::new(),
+        };
+        if ui
+            .button(format!("{} | {}{}", id, kind.unwrap_or("Unknown!"), name))
+            .clicked()
+        {
+            opened = Some((*id as usize, metadata.cloned()));
+        }
+    }
+    if ids.is_empty() {
+        ui.label("No data stored!");
+    }
+    let Some((index_iter, metadata)) = opened else {
+        return;
+    };
+    // Prevents duplicate windows
+    match app
+        .windows
+        .iter()
+        .find(|w| w.device == device && w.selected_data == index_iter)
+    {
+        Some(window) => {
+            app.window_status
+                .entry(window.key())
+                .and_modify(|x| *x = !*x);
+        }
+        None => {
+            let mut window =
+                data_window::DataWindow::new(index_iter.to_string(), device.to_owned(), index_iter);
+            if let Some(metadata) = &metadata {
+                window.set_metadata(metadata);
+            }
+            app.window_status.insert(window.key(), true);
+            app.windows.push(window);
+        }
+    }
+}
+
+/// Channels to export & what's been exported so far
+#[derive(Default)]
+struct ExportDialog {
+    selected: BTreeSet<ChannelKey>,
+    range: Option<(f64, f64)>, // Seconds since the first sample of the selected channels
+    result: Option<String>,    // What the last export did
+}
+
+/// Picks the channels, layout & time range and writes them to a new CSV file
+fn show_export_dialog(app: &mut TemplateApp, ctx: &egui::Context) {
+    let Some(mut dialog) = app.export_dialog.take() else {
+        return;
+    };
+    let data_collection = app.data_collection.clone();
+    let Ok(data) = data_collection.lock() else {
+        eprintln!("Mutex error: Error unlocking whilst exporting");
+        return;
+    };
+    let metadata = |(device, id): &ChannelKey| {
+        app.devices
+            .get(device)
+            .and_then(|state| state.metadata.get(id))
+    };
+    let channels: Vec<(&ChannelKey, String)> = data
+        .iter()
+        .filter(|(_, store)| !store.is_empty())
+        .map(|(key, _)| {
+            (
+                key,
+                channel_label(key, metadata(key).map(|m| m.name.as_str())),
+            )
+        })
+        .collect();
+    // The oldest & newest sample of the selected channels, in unix seconds
+    let span = data
+        .iter()
+        .filter(|(key, _)| dialog.selected.contains(*key))
+        .flat_map(|(_, store)| [store.samples().front(), store.samples().back()])
+        .filter_map(|sample| Some(unix_secs(sample?.time()?.wall)))
+        .fold(None, |span: Option<(f64, f64)>, t| match span {
+            Some((first, last)) => Some((first.min(t), last.max(t))),
+            None => Some((t, t)),
+        });
+    let length = span.map_or(0.0, |(first, last)| last - first);
+
+    let mut open = true;
+    let mut export = false;
+    egui::Window::new("Export to CSV")
+        .collapsible(false)
+        .open(&mut open)
+        .show(ctx, |ui| {
+            ui.label("Channels:");
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for (key, label) in &channels {
+                        let mut selected = dialog.selected.contains(*key);
+                        if ui.checkbox(&mut selected, label).changed() {
+                            match selected {
+                                true => dialog.selected.insert((*key).clone()),
+                                false => dialog.selected.remove(*key),
+                            };
+                        }
+                    }
+                    if channels.is_empty() {
+                        ui.label("No data stored!");
+                    }
+                });
+            ui.separator();
+            app.export_options.ui(ui);
+            ui.horizontal(|ui| {
+                let mut limit = dialog.range.is_some();
+                ui.checkbox(&mut limit, "Only from");
+                let (mut from, mut to) = dialog.range.unwrap_or((0.0, length));
+                ui.add_enabled(
+                    limit,
+                    egui::DragValue::new(&mut from)
+                        .clamp_range(0.0..=length)
+                        .suffix(" s"),
+                );
+                ui.label("to");
+                ui.add_enabled(
+                    limit,
+                    egui::DragValue::new(&mut to)
+                        .clamp_range(from..=length)
+                        .suffix(" s"),
+                );
+                ui.label(format!("of {:.1} s", length));
+                dialog.range = limit.then_some((from, to));
+            });
+            ui.separator();
+            export = ui
+                .add_enabled(!dialog.selected.is_empty(), egui::Button::new("Export"))
+                .clicked();
+            if let Some(result) = &dialog.result {
+                ui.label(result);
+            }
+        });
+
+    if export {
+        let mut columns = Vec::new();
+        for (key, label) in &channels {
+            if !dialog.selected.contains(*key) {
+                continue;
+            }
+            let scaling = metadata(key).map(|m| m.scaling.clone()).unwrap_or_default();
+            columns.extend(export::channel_columns(
+                label,
+                &scaling,
+                data[*key].samples(),
+            ));
+        }
+        let range = span
+            .zip(dialog.range)
+            .map(|((first, _), (from, to))| (first + from, first + to));
+        let path = Path::new(&app.export_options.dir).join(export::new_export_name());
+        dialog.result = match write_export(&path, &columns, &app.export_options, range) {
+            Ok(rows) => Some(format!("Wrote {} rows to {}", rows, path.display())),
+            Err(e) => {
+                eprintln!("{} {}: {}", "Export failed".red(), path.display(), e);
+                app.errors.push(error_message::ErrorInfo::new(
+                    "Export failed".to_owned(),
+                    format!("{}: {}", path.display(), e),
+                    error_message::ErrorSeverity::Minimal,
+                ));
+                None
+            }
+        };
+    }
+    if open {
+        app.export_dialog = Some(dialog);
+    }
+}
+
+fn write_export(
+    path: &Path,
+    columns: &[export::Column],
+    options: &ExportOptions,
+    range: Option<(f64, f64)>,
+) -> std::io::Result<usize> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let rows = export::write_csv(&mut file, columns, options, range)?;
+    file.flush()?;
+    Ok(rows)
+}
+
+/// The file being imported & how its columns are mapped
+#[derive(Default)]
+struct ImportDialog {
+    path: String,
+    rows: Vec<Vec<String>>, // Empty until the file is loaded
+    roles: Vec<ColumnRole>,
+    error: Option<String>,
+}
+
+/// How many rows of the file are shown while mapping the columns
+const IMPORT_PREVIEW_ROWS: usize = 5;
+
+/// Loads a CSV file, maps its columns & imports them as read-only channels
+fn show_import_dialog(app: &mut TemplateApp, ctx: &egui::Context) {
+    let Some(mut dialog) = app.import_dialog.take() else {
+        return;
+    };
+    let mut open = true;
+    let mut import = false;
+    egui::Window::new("Import CSV")
+        .collapsible(false)
+        .open(&mut open)
+        .show(ctx, |ui| {
+            let before = app.import_options.clone();
+            let mut load = false;
+            ui.horizontal(|ui| {
+                ui.label("File:");
+                ui.text_edit_singleline(&mut dialog.path);
+                load = ui.button("Load").clicked();
+            });
+            app.import_options.ui(ui);
+            // The cells & guesses depend on the options, start again when they change
+            if load || (!dialog.rows.is_empty() && app.import_options != before) {
+                match import::read_rows(Path::new(&dialog.path), app.import_options.delimiter) {
+                    Ok(rows) => {
+                        dialog.roles = import::guess_roles(&rows, &app.import_options);
+                        dialog.rows = rows;
+                        dialog.error = None;
+                    }
+                    Err(e) => {
+                        dialog.rows.clear();
+                        dialog.error = Some(e.to_string());
+                    }
+                }
+            }
+            if !dialog.rows.is_empty() {
+                ui.separator();
+                show_column_mapping(ui, &mut dialog, app.import_options.header);
+                ui.separator();
+                import = ui.button("Import").clicked();
+            }
+            if let Some(error) = &dialog.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+
+    if import {
+        let options = &app.import_options;
+        match import::import_channels(&dialog.rows, &dialog.roles, options, SampleTime::now()) {
+            Ok(channels) => {
+                let device = import::import_device(Path::new(&dialog.path));
+                app.add_import(&device, channels);
+                open = false;
+            }
+            Err(e) => dialog.error = Some(e.to_string()),
+        }
+    }
+    if open {
+        app.import_dialog = Some(dialog);
+    }
+}
+
+/// A column for each column of the file, picking what it is, with the first few rows under it
+fn show_column_mapping(ui: &mut egui::Ui, dialog: &mut ImportDialog, header: bool) {
+    let preview = &dialog.rows[usize::from(header)..];
+    egui::ScrollArea::horizontal().show(ui, |ui| {
+        egui::Grid::new("import_columns")
+            .striped(true)
+            .show(ui, |ui| {
+                for (i, role) in dialog.roles.iter_mut().enumerate() {
+                    let is_channel = matches!(role, ColumnRole::Channel { .. });
+                    let heading = match header {
+                        true => dialog.rows[0].get(i).cloned().unwrap_or_default(),
+                        false => format!("Column {}", i + 1),
+                    };
+                    egui::ComboBox::from_id_source(("import_role", i))
+                        .selected_text(match role {
+                            ColumnRole::Skip => "Skip",
+                            ColumnRole::Time => "Time",
+                            ColumnRole::Channel { .. } => "Channel",
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(role, ColumnRole::Skip, "Skip");
+                            ui.selectable_value(role, ColumnRole::Time, "Time");
+                            if ui.selectable_label(is_channel, "Channel").clicked() && !is_channel {
+                                *role = ColumnRole::Channel {
+                                    name: heading,
+                                    unit: String::new(),
+                                };
                             }
+                        });
+                }
+                ui.end_row();
+                for role in dialog.roles.iter_mut() {
+                    match role {
+                        ColumnRole::Channel { name, unit } => {
+                            ui.vertical(|ui| {
+                                ui.add(egui::TextEdit::singleline(name).hint_text("Name"));
+                                ui.add(egui::TextEdit::singleline(unit).hint_text("Unit"));
+                            });
+                        }
+                        _ => {
+                            ui.label("");
                         }
                     }
                 }
+                ui.end_row();
+                for row in preview.iter().take(IMPORT_PREVIEW_ROWS) {
+                    for i in 0..dialog.roles.len() {
+                        ui.label(row.get(i).map_or("", String::as_str));
+                    }
+                    ui.end_row();
+                }
             });
-        }
+    });
+    if preview.len() > IMPORT_PREVIEW_ROWS {
+        ui.label(format!("{} more rows", preview.len() - IMPORT_PREVIEW_ROWS));
+    }
+    if dialog
+        .roles
+        .iter()
+        .filter(|r| **r == ColumnRole::Time)
+        .count()
+        > 1
+    {
+        ui.label("Only the first time column is used");
     }
 }
 
-fn show_available_ports(app: &mut TemplateApp, ui: &mut egui::Ui) {
-    let ports = tokio_serial::available_ports();
-    match ports {
-        Err(e) => {
-            ui.label("Error finding serial ports!");
-            eprintln!("{:?}", e);
+/// Retention settings & how much memory the data is using
+fn show_storage_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    ui.menu_button("Storage", |ui| {
+        let (samples, bytes) = match app.data_collection.lock() {
+            Ok(data) => data.values().fold((0, 0), |(samples, bytes), channel| {
+                (samples + channel.len(), bytes + channel.memory_usage())
+            }),
+            Err(_) => (0, 0),
+        };
+        ui.label(format!(
+            "Storing {} samples using {}",
+            samples,
+            format_bytes(bytes)
+        ));
+        ui.separator();
+        app.retention.ui(ui);
+        ui.label("Changes apply as new data arrives");
+    });
+}
+
+/// Starts & stops recording everything received to a capture file
+fn show_record_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    let title = match app.recording {
+        Some(_) => "⏺ Recording",
+        None => "Record",
+    };
+    ui.menu_button(title, |ui| match app.recording.clone() {
+        Some(path) => {
+            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            ui.label(format!(
+                "Recording to {} ({})",
+                path.display(),
+                format_bytes(size as usize)
+            ));
+            if ui.button("⏹ Stop recording").clicked() {
+                app.recording = None;
+                send_thread_msg(app.tx.clone(), ThreadMSG::StopRecording());
+                ui.close_menu();
+            }
         }
-        Ok(ports) => 'port: {
-            if ports.len() == 0 {
-                ui.label("No serial devices found!");
-                break 'port;
+        None => {
+            ui.horizontal(|ui| {
+                ui.label("Save in:");
+                ui.text_edit_singleline(&mut app.capture_dir);
+            });
+            if ui.button("⏺ Start recording").clicked() {
+                let path = Path::new(&app.capture_dir).join(capture::new_capture_name());
+                app.recording = Some(path.clone());
+                send_thread_msg(app.tx.clone(), ThreadMSG::StartRecording(path));
+                ui.close_menu();
             }
-            for port in ports.iter() {
-                if port.port_name.eq_ignore_ascii_case(&app.selected_port) {
-                    ui.label(format!("{} (Connected)", &port.port_name));
-                } else {
-                    ui.label(format!("{}", &port.port_name));
+        }
+    });
+}
+
+/// Opens a capture file to play back, the captures in the recording folder are listed
+fn show_replay_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    ui.menu_button("Replay", |ui| {
+        let mut open = None;
+        ui.horizontal(|ui| {
+            ui.label("File:");
+            ui.text_edit_singleline(&mut app.replay_path);
+            if ui.button("Open").clicked() {
+                open = Some(PathBuf::from(&app.replay_path));
+            }
+        });
+        let mut captures: Vec<PathBuf> = std::fs::read_dir(&app.capture_dir)
+            .map(|dir| {
+                dir.filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| {
+                        path.extension()
+                            .map_or(false, |ext| ext == capture::EXTENSION)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        captures.sort();
+        if !captures.is_empty() {
+            ui.separator();
+        }
+        // Newest first
+        for path in captures.into_iter().rev() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if ui.button(name).clicked() {
+                open = Some(path.clone());
+            }
+        }
+        if let Some(path) = open {
+            app.replay_path = path.display().to_string();
+            app.replay_id += 1;
+            let msg = ThreadMSG::StartReplay((path, app.replay_id));
+            send_thread_msg(app.tx.clone(), msg);
+            ui.close_menu();
+        }
+    });
+}
+
+/// Play, pause, step, seek, speed & loop controls for the open replay
+fn show_replay_window(app: &mut TemplateApp, ctx: &egui::Context) {
+    let Some(status) = app.replay.clone() else {
+        return;
+    };
+    let mut commands = Vec::new();
+    let mut open = true;
+    egui::Window::new("Replay")
+        .resizable(false)
+        .open(&mut open)
+        .show(ctx, |ui| {
+            ui.label(status.path.display().to_string());
+            if status.truncated {
+                ui.label("The end of this capture was damaged, the last record is missing");
+            }
+            ui.horizontal(|ui| {
+                match status.playing {
+                    true if ui.button("⏸ Pause").clicked() => commands.push(ReplayCommand::Pause),
+                    false if ui.button("⏵ Play").clicked() => commands.push(ReplayCommand::Play),
+                    _ => (),
+                }
+                if ui.button("⏭ Step").clicked() {
+                    commands.push(ReplayCommand::Step);
+                }
+                let mut looping = status.looping;
+                if ui.checkbox(&mut looping, "Loop").changed() {
+                    commands.push(ReplayCommand::Loop(looping));
                 }
+                let mut speed = status.speed;
+                egui::ComboBox::from_id_source("replay_speed")
+                    .selected_text(format!("{}x", speed))
+                    .show_ui(ui, |ui| {
+                        for preset in REPLAY_SPEEDS {
+                            ui.selectable_value(&mut speed, preset, format!("{}x", preset));
+                        }
+                    });
+                if speed != status.speed {
+                    commands.push(ReplayCommand::Speed(speed));
+                }
+            });
+            // The position keeps moving while playing, hold it still while it's being dragged
+            let length = status.length.as_secs_f64();
+            let mut position = app.replay_seek.unwrap_or(status.position.as_secs_f64());
+            let response = ui.add(
+                egui::Slider::new(&mut position, 0.0..=length)
+                    .suffix(format!(" s of {:.1} s", length))
+                    .fixed_decimals(1),
+            );
+            if response.dragged() {
+                app.replay_seek = Some(position);
+            } else if response.drag_released() || response.changed() {
+                app.replay_seek = None;
+                commands.push(ReplayCommand::Seek(Duration::from_secs_f64(position)));
             }
+        });
+    if !open {
+        commands.push(ReplayCommand::Stop);
+        app.replay = None;
+    }
+    for command in commands {
+        send_thread_msg(app.tx.clone(), ThreadMSG::ReplayControl(command));
+    }
+}
+
+/// Opens the raw terminal for any device that has sent something
+fn show_terminal_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    ui.menu_button("Terminal", |ui| {
+        if app.terminals.is_empty() {
+            ui.label("Nothing received yet!");
+        }
+        for (device, terminal) in app.terminals.iter_mut() {
+            ui.checkbox(&mut terminal.open, device);
+        }
+    });
+}
+
+/// Lists what each device is doing
+fn show_device_status(app: &TemplateApp, ui: &mut egui::Ui) {
+    for (device, state) in app.devices.iter().filter(|(_, s)| s.connected) {
+        if let Some(attempt) = state.reconnect_attempt {
+            ui.label(format!(
+                "Lost connection to {}, reconnecting (attempt {})...",
+                device, attempt
+            ));
+        } else if let Some(descriptor) = &state.descriptor {
+            ui.label(format!(
+                "{}: {} (firmware {}, protocol v{}) with {} channels",
+                device,
+                descriptor.name,
+                descriptor.firmware_version,
+                descriptor.protocol_version,
+                state.channels.len()
+            ));
+        } else {
+            ui.label(format!("{}: Connected on {}", device, state.port_path));
+        }
+    }
+}
+
+fn show_available_ports(app: &mut TemplateApp, ui: &mut egui::Ui) {
+    let ports = app.ports.clone();
+    match ports.is_empty() {
+        true => {
+            ui.label("No serial devices found!");
+        }
+        false => {
+            egui::Grid::new("available_ports")
+                .num_columns(3)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Port");
+                    ui.strong("Alias");
+                    ui.strong("Device");
+                    ui.end_row();
+                    for port in ports.iter() {
+                        let attempt = app
+                            .device_on_port(&port.port_name)
+                            .map(|device| app.devices[device].reconnect_attempt);
+                        match attempt {
+                            Some(Some(attempt)) => ui.label(format!(
+                                "{} (Reconnecting, attempt {})",
+                                &port.port_name, attempt
+                            )),
+                            Some(None) => ui.label(format!("{} (Connected)", &port.port_name)),
+                            None => ui.label(&port.port_name),
+                        };
+                        // Only USB boards can be recognised again, so only they get aliases
+                        match PortIdentity::of(port) {
+                            Some(identity) => {
+                                let key = identity.to_string();
+                                let mut alias =
+                                    app.port_aliases.get(&key).cloned().unwrap_or_default();
+                                if ui
+                                    .add(
+                                        egui::TextEdit::singleline(&mut alias).desired_width(100.0),
+                                    )
+                                    .changed()
+                                {
+                                    match alias.is_empty() {
+                                        true => app.port_aliases.remove(&key),
+                                        false => app.port_aliases.insert(key, alias),
+                                    };
+                                }
+                            }
+                            None => {
+                                ui.label("");
+                            }
+                        }
+                        ui.label(ports::describe(port));
+                        ui.end_row();
+                    }
+                });
         }
     }
 }
 
+/// The port's alias with its name, or just the name if it has no alias
+fn port_label(app: &TemplateApp, port: &SerialPortInfo) -> String {
+    let alias = PortIdentity::of(port).and_then(|i| app.port_aliases.get(&i.to_string()));
+    match alias {
+        Some(alias) => format!("{} ({})", alias, port.port_name),
+        None => port.port_name.clone(),
+    }
+}
+
+/// Offers to connect to the board used last time once it's plugged in, only once per session
+fn auto_select_port(app: &mut TemplateApp) {
+    if app.auto_selected || app.connect_dialog.is_some() {
+        return;
+    }
+    let Some(identity) = &app.last_identity else {
+        return;
+    };
+    let found = ports::find_port(&app.ports, "", Some(identity));
+    if let Some(port_name) = found.filter(|p| app.device_on_port(p).is_none()) {
+        let settings = app
+            .port_settings
+            .get(&port_name)
+            .cloned()
+            .unwrap_or_default();
+        app.connect_dialog = Some((port_name, settings));
+        app.auto_selected = true;
+    }
+}
+
 fn show_port_menu(app: &mut TemplateApp, ui: &mut egui::Ui) {
-    let ports = tokio_serial::available_ports();
-    ui.menu_button("Ports", |ui| match ports {
-        Err(e) => {
+    let ports = app.ports.clone();
+    ui.menu_button("Ports", |ui| {
+        // The board may be gone from the list while it's being reconnected to
+        let reconnecting: Vec<String> = app
+            .devices
+            .iter()
+            .filter(|(_, state)| state.connected && state.reconnect_attempt.is_some())
+            .map(|(device, _)| device.clone())
+            .collect();
+        for device in reconnecting {
+            if ui
+                .button(format!("{} | Stop reconnecting", device))
+                .clicked()
+            {
+                app.disconnect(&device);
+            }
+        }
+        show_port_list(app, ui, &ports);
+    });
+}
+
+fn show_port_list(app: &mut TemplateApp, ui: &mut egui::Ui, ports: &[SerialPortInfo]) {
+    match ports.is_empty() {
+        true => {
             ui.label("No ports found!");
-            println!("{:?}", e);
         }
-        Ok(ports) => 'port: {
-            if ports.len() == 0 {
-                ui.label("No ports found!");
-                break 'port;
-            }
+        false => {
             for port in ports.iter() {
-                if port.port_name.eq_ignore_ascii_case(&app.selected_port) {
+                if let Some(device) = app.device_on_port(&port.port_name) {
+                    let device = device.to_owned();
+                    let settings = &app.devices[&device].settings;
                     if ui
-                        .button(format!("{} | Disconnect", &port.port_name))
+                        .button(format!(
+                            "{} ({}) | Disconnect",
+                            port_label(app, port),
+                            settings
+                        ))
+                        .on_hover_text(ports::describe(port))
                         .clicked()
                     {
-                        app.selected_port = "Disconnected".to_owned();
-                        send_thread_msg(app.tx.clone(), ThreadMSG::Disconnect());
-                    }
-                } else {
-                    if ui.button(port.port_name.clone()).clicked() {
-                        app.selected_port = port.port_name.clone();
-                        app.arduino
-                            .lock()
-                            .unwrap()
-                            .connect(app.selected_port.clone(), 9600);
-                        send_thread_msg(
-                            app.tx.clone(),
-                            ThreadMSG::Start((port.port_name.clone(), 9600)),
-                        );
+                        app.disconnect(&device);
                     }
+                } else if ui
+                    .button(port_label(app, port))
+                    .on_hover_text(ports::describe(port))
+                    .clicked()
+                {
+                    let settings = app
+                        .port_settings
+                        .get(&port.port_name)
+                        .cloned()
+                        .unwrap_or_default();
+                    app.connect_dialog = Some((port.port_name.clone(), settings));
+                    ui.close_menu();
                 }
             }
         }
-    });
+    }
+}
+
+/// Lets the line settings be changed before connecting, they're remembered for the port
+fn show_connect_dialog(app: &mut TemplateApp, ctx: &egui::Context) {
+    let Some((port_name, settings)) = &mut app.connect_dialog else {
+        return;
+    };
+    let mut open = true;
+    let mut connect = false;
+    egui::Window::new(format!("Connect to {}", port_name))
+        .collapsible(false)
+        .resizable(false)
+        .open(&mut open)
+        .show(ctx, |ui| {
+            settings.ui(ui);
+            ui.separator();
+            ui.horizontal(|ui| {
+                connect = ui.button("Connect").clicked();
+                if ui.button("Reset to defaults").clicked() {
+                    *settings = SerialSettings::default();
+                }
+            });
+        });
+    if connect {
+        let (port_name, settings) = app.connect_dialog.take().unwrap();
+        app.port_settings
+            .insert(port_name.clone(), settings.clone());
+        app.last_identity = app
+            .ports
+            .iter()
+            .find(|p| p.port_name == port_name)
+            .and_then(PortIdentity::of);
+        app.connect(port_name, settings);
+    } else if !open {
+        app.connect_dialog = None;
+    }
+}
+
+/// Pops up when a board is plugged in, offering to connect to it
+fn show_notifications(app: &mut TemplateApp, ctx: &egui::Context) {
+    app.notifications
+        .retain(|(_, appeared)| appeared.elapsed() < NOTIFICATION_TIME);
+    // Nothing else may repaint when the oldest one is due to go
+    if let Some((_, appeared)) = app.notifications.first() {
+        ctx.request_repaint_after(NOTIFICATION_TIME.saturating_sub(appeared.elapsed()));
+    }
+    let mut dismissed = Vec::new();
+    let mut connect = None;
+    for (i, (port, _)) in app.notifications.iter().enumerate() {
+        egui::Window::new("New device")
+            .id(egui::Id::new(("new_device", &port.port_name)))
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0 - 90.0 * i as f32])
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!("{} was plugged in", port_label(app, port)));
+                ui.label(ports::describe(port));
+                ui.horizontal(|ui| {
+                    if ui.button("Connect").clicked() {
+                        dismissed.push(port.port_name.clone());
+                        connect = Some(port.port_name.clone());
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        dismissed.push(port.port_name.clone());
+                    }
+                });
+            });
+    }
+    app.notifications
+        .retain(|(p, _)| !dismissed.contains(&p.port_name));
+    if let Some(port_name) = connect {
+        let settings = app
+            .port_settings
+            .get(&port_name)
+            .cloned()
+            .unwrap_or_default();
+        app.connect_dialog = Some((port_name, settings));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, secs: u64) -> SampleTime {
+        SampleTime {
+            host: start + Duration::from_secs(secs),
+            ..SampleTime::now()
+        }
+    }
+
+    #[test]
+    fn trims_idle_channels_and_says_when_to_wake() {
+        let mut app = TemplateApp {
+            retention: Retention {
+                max_samples: None,
+                max_age: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let start = Instant::now();
+        app.store("COM3", PacketData::Float(1.0, 0, at(start, 0)));
+        app.store("COM3", PacketData::Float(2.0, 0, at(start, 4)));
+        let len =
+            |app: &TemplateApp| app.data_collection.lock().unwrap()[&("COM3".to_owned(), 0)].len();
+        let wake = app.trim_channels(start + Duration::from_secs(8));
+        assert_eq!(wake, Some(Duration::from_millis(2_001)));
+        assert_eq!(len(&app), 2);
+        let wake = app.trim_channels(start + Duration::from_secs(12));
+        assert_eq!(wake, Some(Duration::from_millis(2_001)));
+        assert_eq!(len(&app), 1);
+        assert_eq!(app.trim_channels(start + Duration::from_secs(15)), None);
+        assert_eq!(len(&app), 0);
+    }
+
+    #[test]
+    fn handles_every_waiting_message_in_one_frame() {
+        let (tx, rx) = mpsc::channel(256);
+        let mut app = TemplateApp {
+            rx,
+            ..Default::default()
+        };
+        for i in 0..200 {
+            let device = ["COM3", "COM4"][i % 2].to_owned();
+            let data = PacketData::Integer(i as i128, 1, SampleTime::now());
+            tx.try_send((device, ThreadMSG::Data(data))).unwrap();
+        }
+        app.receive_messages(&egui::Context::default());
+        assert!(matches!(app.rx.try_recv(), Err(TryRecvError::Empty)));
+        let data = app.data_collection.lock().unwrap();
+        assert_eq!(data[&("COM3".to_owned(), 1)].len(), 100);
+        assert_eq!(data[&("COM4".to_owned(), 1)].len(), 100);
+    }
+
+    #[test]
+    fn ignores_statuses_from_an_old_replay() {
+        let mut app = TemplateApp {
+            replay_id: 2,
+            ..Default::default()
+        };
+        let status = |id, stopped: bool| ReplayStatus {
+            id,
+            path: PathBuf::from("capture.acap"),
+            position: Duration::ZERO,
+            length: Duration::from_secs(60),
+            playing: !stopped,
+            speed: 1.0,
+            looping: false,
+            truncated: false,
+            stopped,
+        };
+        app.handle_msg(String::new(), ThreadMSG::ReplayStatus(status(2, false)));
+        // The replay that was replaced only says it has stopped after the new one has started
+        app.handle_msg(String::new(), ThreadMSG::ReplayStatus(status(1, true)));
+        let msg = ThreadMSG::ReplayFailed((1, "gone".to_owned()));
+        app.handle_msg(String::new(), msg);
+        assert_eq!(app.replay.as_ref().map(|r| r.id), Some(2));
+        assert!(app.errors.is_empty());
+        app.handle_msg(String::new(), ThreadMSG::ReplayStatus(status(2, true)));
+        assert!(app.replay.is_none());
+    }
 }
@@ -1,76 +1,234 @@
-use std::{
-    time::{Duration, Instant, SystemTime},
-    usize,
-};
+use std::{fmt::Display, path::PathBuf, time::Duration, usize};
 
 use colored::Colorize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
-use tokio_serial::SerialPortBuilderExt;
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+
+use crate::clock::{DeviceClock, SampleTime};
+use crate::connection::DeviceMSG;
+use crate::device::{
+    ChannelDescriptor, ChannelMetadata, DeviceDescriptor, KIND_CHANNEL, KIND_DESCRIPTOR,
+    KIND_METADATA,
+};
+use crate::frame::{Frame, FrameDecoder, FrameError, PROTOCOL_VERSION};
+use crate::layout::{FieldType, FieldValue};
+use crate::ports::{self, PortIdentity};
+use crate::replay::{ReplayCommand, ReplayStatus};
+use crate::serial_settings::{FlowControl, SerialSettings};
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct Arduino {
-    pub port: Option<Box<dyn tokio_serial::SerialPort>>,
+    pub device: String, // Which connection this is, data sent to the GUI is tagged with it
+    port: Option<SerialStream>, // Only the reader task has the port
     pub baud_rate: Option<u32>,
     serial_buffer: Vec<u8>,
+    decoder: PacketDecoder,
+    connection: Option<Connection>, // Kept after the port is lost so it can be reopened
 }
 
+/// What was connected to, used to find the board again if it goes away
 #[derive(Debug, Clone)]
+struct Connection {
+    port_path: String,
+    settings: SerialSettings,
+    identity: Option<PortIdentity>,
+}
+
+/// Waits between reconnect attempts, doubling each time up to the maximum
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PacketData {
-    Integer(isize, u8, Instant),
-    String(String, u8, Instant),
-    Float(f64, u8, Instant),
+    Integer(i128, u8, SampleTime), // Wide enough to hold every integer kind, including u64
+    String(String, u8, SampleTime),
+    Float(f64, u8, SampleTime),
+    Binary(Vec<u8>, u8, SampleTime),
+    Vector(Vec<FieldValue>, u8, SampleTime), // Several values that were sampled together
+    Gap(u8, SampleTime), // The connection was lost, nothing was received until the next sample
+    Descriptor(DeviceDescriptor), // What the device is, sent in reply to the hello
+    Channel(ChannelDescriptor), // What the device sends under one packet ID
+    Metadata(ChannelMetadata), // Name, unit & scaling for one packet ID
     None(),
 }
 
 impl PacketData {
-    pub fn display_variant(&self) -> &str {
+    pub fn display_variant(&self) -> &'static str {
         match self {
             Self::Integer(_, _, _) => "Integer",
             Self::String(_, _, _) => "String",
             Self::Float(_, _, _) => "Float",
+            Self::Binary(_, _, _) => "Binary",
+            Self::Vector(_, _, _) => "Vector",
+            Self::Gap(_, _) => "Gap",
+            Self::Descriptor(_) => "Descriptor",
+            Self::Channel(_) => "Channel",
+            Self::Metadata(_) => "Metadata",
             _ => "None",
         }
     }
+
+    /// The sample as numbers, one for each field. Only numeric samples have values
+    pub fn values(&self) -> Option<Vec<f64>> {
+        match self {
+            Self::Integer(d, _, _) => Some(vec![*d as f64]),
+            Self::Float(d, _, _) => Some(vec![*d]),
+            Self::Vector(d, _, _) => Some(d.iter().map(FieldValue::as_f64).collect()),
+            _ => None,
+        }
+    }
+
+    pub fn time(&self) -> Option<&SampleTime> {
+        match self {
+            Self::Integer(_, _, t)
+            | Self::String(_, _, t)
+            | Self::Float(_, _, t)
+            | Self::Binary(_, _, t)
+            | Self::Vector(_, _, t)
+            | Self::Gap(_, t) => Some(t),
+            Self::Descriptor(_) | Self::Channel(_) | Self::Metadata(_) | Self::None() => None,
+        }
+    }
+
+    pub fn time_mut(&mut self) -> Option<&mut SampleTime> {
+        match self {
+            Self::Integer(_, _, t)
+            | Self::String(_, _, t)
+            | Self::Float(_, _, t)
+            | Self::Binary(_, _, t)
+            | Self::Vector(_, _, t)
+            | Self::Gap(_, t) => Some(t),
+            Self::Descriptor(_) | Self::Channel(_) | Self::Metadata(_) | Self::None() => None,
+        }
+    }
+
+    /// The packet ID of a sample
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Self::Integer(_, id, _)
+            | Self::String(_, id, _)
+            | Self::Float(_, id, _)
+            | Self::Binary(_, id, _)
+            | Self::Vector(_, id, _)
+            | Self::Gap(id, _) => Some(*id),
+            Self::Descriptor(_) | Self::Channel(_) | Self::Metadata(_) | Self::None() => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ThreadMSG {
-    Start((String, usize)), // Port path & baud rate
-    Data(PacketData),       // Data ID & Data
-    Disconnect(),
+    Start((String, SerialSettings)),       // Port path & line settings
+    Data(PacketData),                      // Data ID & Data
+    Disconnect(String),                    // Device to disconnect
+    ConnectFailed((String, ConnectError)), // Port path & why it couldn't be opened
+    ConnectionLost((String, String)),      // Port path & the read error
+    Reconnecting((String, u32)),           // Port path & attempt number
+    Reconnected(String),                   // Port path, which may have changed
+    Raw((Vec<u8>, SampleTime)),            // Bytes as they were read & when
+    StartRecording(PathBuf),               // Capture file to create
+    StopRecording(),
+    RecordingFailed(String),     // Why the recording stopped
+    StartReplay((PathBuf, u64)), // Capture file to play back & an ID for the replay
+    ReplayControl(ReplayCommand),
+    ReplayStatus(ReplayStatus),
+    ReplayFailed((u64, String)), // The replay's ID & why the capture couldn't be played
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum PacketKind {
     String,
-    PosInteger,
-    NegInteger,
+    PosInteger,         // Legacy, the sign is given by the kind instead of the data
+    NegInteger,         // Legacy
+    Integer(FieldType), // Fixed width, little-endian & two's complement
     Binary,
-    Float,
+    AsciiFloat, // Legacy, the float is sent as text using dtostrf
+    Float32,    // Little-endian IEEE-754
+    Float64,    // Little-endian IEEE-754
+    Vector,     // Several tagged values, see Packet::read_vector
+    Descriptor, // See crate::device
+    Channel,
+    Metadata,
     Unknown,
 }
 
-impl Into<PacketKind> for u8 {
-    fn into(self) -> PacketKind {
-        match self {
+impl From<u8> for PacketKind {
+    fn from(kind: u8) -> Self {
+        match kind {
             1 => PacketKind::String,
             2 => PacketKind::PosInteger,
             3 => PacketKind::NegInteger,
             4 => PacketKind::Binary,
-            5 => PacketKind::Float,
+            5 => PacketKind::AsciiFloat,
+            6 => PacketKind::Float32,
+            7 => PacketKind::Float64,
+            8 => PacketKind::Integer(FieldType::U8),
+            9 => PacketKind::Integer(FieldType::I8),
+            10 => PacketKind::Integer(FieldType::U16),
+            11 => PacketKind::Integer(FieldType::I16),
+            12 => PacketKind::Integer(FieldType::U32),
+            13 => PacketKind::Integer(FieldType::I32),
+            14 => PacketKind::Integer(FieldType::U64),
+            15 => PacketKind::Integer(FieldType::I64),
+            16 => PacketKind::Vector,
+            KIND_DESCRIPTOR => PacketKind::Descriptor,
+            KIND_CHANNEL => PacketKind::Channel,
+            KIND_METADATA => PacketKind::Metadata,
             _ => PacketKind::Unknown,
         }
     }
 }
 
+impl PacketKind {
+    /// The kind byte sent on the wire, the reverse of From<u8>
+    pub fn code(&self) -> u8 {
+        match self {
+            PacketKind::String => 1,
+            PacketKind::PosInteger => 2,
+            PacketKind::NegInteger => 3,
+            PacketKind::Binary => 4,
+            PacketKind::AsciiFloat => 5,
+            PacketKind::Float32 | PacketKind::Integer(FieldType::F32) => 6,
+            PacketKind::Float64 | PacketKind::Integer(FieldType::F64) => 7,
+            PacketKind::Integer(FieldType::U8) => 8,
+            PacketKind::Integer(FieldType::I8) => 9,
+            PacketKind::Integer(FieldType::U16) => 10,
+            PacketKind::Integer(FieldType::I16) => 11,
+            PacketKind::Integer(FieldType::U32) => 12,
+            PacketKind::Integer(FieldType::I32) => 13,
+            PacketKind::Integer(FieldType::U64) => 14,
+            PacketKind::Integer(FieldType::I64) => 15,
+            PacketKind::Vector => 16,
+            PacketKind::Descriptor => KIND_DESCRIPTOR,
+            PacketKind::Channel => KIND_CHANNEL,
+            PacketKind::Metadata => KIND_METADATA,
+            PacketKind::Unknown => 0,
+        }
+    }
+}
+
+/// Values in a vector packet are tagged with the packet kind they would be sent as on their own,
+/// only the fixed size number kinds can be used
+fn vector_field_type(tag: u8) -> Option<FieldType> {
+    match PacketKind::from(tag) {
+        PacketKind::Float32 => Some(FieldType::F32),
+        PacketKind::Float64 => Some(FieldType::F64),
+        PacketKind::Integer(field_type) => Some(field_type),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct Packet {
     packet_type: PacketKind,
     packet_id: u8, // The arduino will probably send data relating to multiple things, this will
     // allow for the packet to be assigned to something
     raw_data: Vec<u8>,
+    time: SampleTime,
     constructed_data: PacketData,
+    log: bool, // Text printed between frames, sent as the device's log
 }
 
 impl Packet {
@@ -79,43 +237,443 @@ impl Packet {
             packet_type,
             packet_id,
             raw_data,
+            time: SampleTime::now(),
             constructed_data: PacketData::None(),
+            log: false,
+        }
+    }
+
+    pub fn data(&self) -> &PacketData {
+        &self.constructed_data
+    }
+
+    /// A packet for the log channel, made up from text rather than received as a frame
+    fn log(data: PacketData) -> Self {
+        Self {
+            packet_type: PacketKind::String,
+            packet_id: LOG_CHANNEL,
+            raw_data: Vec::new(),
+            time: data.time().copied().unwrap_or_else(SampleTime::now),
+            constructed_data: data,
+            log: true,
+        }
+    }
+
+    /// The packet is for the device's log, see `log_device`
+    pub fn is_log(&self) -> bool {
+        self.log
+    }
+
+    /// Converts the payload to a utf-8 ASCII string
+    fn read_string(&mut self) {
+        let mut tmp_string: String = "".to_owned();
+        for byte in self.raw_data.iter() {
+            if *byte != 0 {
+                tmp_string.push(*byte as char);
+            }
+        }
+        self.constructed_data = PacketData::String(tmp_string, self.packet_id, self.time);
+    }
+
+    /// Keeps the payload as raw bytes, the GUI decides how to show them
+    fn read_binary(&mut self) {
+        self.constructed_data =
+            PacketData::Binary(self.raw_data.clone(), self.packet_id, self.time);
+    }
+
+    /// Converts the payload to a string and then parses to float
+    fn read_ascii_float(&mut self) -> Result<(), PacketError> {
+        let mut tmp_string: String = "".to_owned();
+        for byte in self.raw_data.iter() {
+            if *byte != 0 {
+                tmp_string.push(*byte as char);
+            }
+        }
+        let float_value = tmp_string
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| PacketError::InvalidPayload(format!("'{}' is not a float", tmp_string)))?;
+        self.constructed_data = PacketData::Float(float_value, self.packet_id, self.time);
+        Ok(())
+    }
+
+    /// Reads the payload as a little-endian f32
+    fn read_f32(&mut self) -> Result<(), PacketError> {
+        let bytes = self.fixed_payload::<4>()?;
+        let float_value = f32::from_le_bytes(bytes) as f64;
+        self.constructed_data = PacketData::Float(float_value, self.packet_id, self.time);
+        Ok(())
+    }
+
+    /// Reads the payload as a little-endian f64
+    fn read_f64(&mut self) -> Result<(), PacketError> {
+        let bytes = self.fixed_payload::<8>()?;
+        let float_value = f64::from_le_bytes(bytes);
+        self.constructed_data = PacketData::Float(float_value, self.packet_id, self.time);
+        Ok(())
+    }
+
+    /// Returns the payload as an array, errors if the payload isn't exactly N bytes long
+    fn fixed_payload<const N: usize>(&self) -> Result<[u8; N], PacketError> {
+        self.raw_data.as_slice().try_into().map_err(|_| {
+            PacketError::InvalidPayload(format!(
+                "Expected {} bytes of data, found {}",
+                N,
+                self.raw_data.len()
+            ))
+        })
+    }
+
+    /// Converts the payload to an integer for the legacy integer kinds. The payload is a
+    /// little-endian two's complement integer of any width up to 8 bytes, the kind says whether
+    /// it should be sign extended
+    fn read_legacy_integer(&mut self, is_negative: bool) -> Result<(), PacketError> {
+        if self.raw_data.is_empty() || self.raw_data.len() > 8 {
+            return Err(PacketError::InvalidPayload(format!(
+                "Integer can't be {} bytes long",
+                self.raw_data.len()
+            )));
+        }
+        let fill = if is_negative { 0xFF } else { 0x00 };
+        let mut bytes = [fill; 8];
+        bytes[..self.raw_data.len()].copy_from_slice(&self.raw_data);
+        let value = match is_negative {
+            true => i64::from_le_bytes(bytes) as i128,
+            false => u64::from_le_bytes(bytes) as i128,
+        };
+        self.constructed_data = PacketData::Integer(value, self.packet_id, self.time);
+        Ok(())
+    }
+
+    /// Reads the payload as a list of values, each value is a tag byte giving its type followed
+    /// by the value itself in little-endian
+    fn read_vector(&mut self) -> Result<(), PacketError> {
+        let mut values = Vec::new();
+        let mut rest = self.raw_data.as_slice();
+        while let Some((tag, bytes)) = rest.split_first() {
+            let field_type = vector_field_type(*tag).ok_or_else(|| {
+                PacketError::InvalidPayload(format!(
+                    "Value {} has unknown type {}",
+                    values.len(),
+                    tag
+                ))
+            })?;
+            let value = field_type.read(bytes).ok_or_else(|| {
+                PacketError::InvalidPayload(format!("Value {} is cut short", values.len()))
+            })?;
+            values.push(value);
+            rest = &bytes[field_type.size()..];
+        }
+        self.constructed_data = PacketData::Vector(values, self.packet_id, self.time);
+        Ok(())
+    }
+
+    /// Converts the payload to an integer of the given width
+    fn read_integer(&mut self, field_type: FieldType) -> Result<(), PacketError> {
+        if self.raw_data.len() != field_type.size() {
+            return Err(PacketError::InvalidPayload(format!(
+                "Expected {} bytes for {:?}, found {}",
+                field_type.size(),
+                field_type,
+                self.raw_data.len()
+            )));
+        }
+        let value = match field_type.read(&self.raw_data) {
+            Some(FieldValue::Unsigned(v)) => v as i128,
+            Some(FieldValue::Signed(v)) => v as i128,
+            _ => {
+                return Err(PacketError::InvalidPayload(format!(
+                    "{:?} is not an integer",
+                    field_type
+                )))
+            }
+        };
+        self.constructed_data = PacketData::Integer(value, self.packet_id, self.time);
+        Ok(())
+    }
+}
+
+impl Packet {
+    /// Determines the type of the packet from the frame and then calls the appropriate read
+    /// function
+    pub fn decode(frame: Frame, time: SampleTime) -> Result<Self, PacketError> {
+        let mut packet = Self::new(frame.kind.into(), frame.id, frame.payload);
+        packet.time = time;
+        match packet.packet_type {
+            PacketKind::String => packet.read_string(),
+            PacketKind::PosInteger => packet.read_legacy_integer(false)?,
+            PacketKind::NegInteger => packet.read_legacy_integer(true)?,
+            PacketKind::Integer(field_type) => packet.read_integer(field_type)?,
+            PacketKind::Binary => packet.read_binary(),
+            PacketKind::AsciiFloat => packet.read_ascii_float()?,
+            PacketKind::Float32 => packet.read_f32()?,
+            PacketKind::Float64 => packet.read_f64()?,
+            PacketKind::Vector => packet.read_vector()?,
+            PacketKind::Descriptor => {
+                packet.constructed_data =
+                    PacketData::Descriptor(DeviceDescriptor::parse(&packet.raw_data)?)
+            }
+            PacketKind::Channel => {
+                packet.constructed_data =
+                    PacketData::Channel(ChannelDescriptor::parse(&packet.raw_data)?)
+            }
+            PacketKind::Metadata => {
+                packet.constructed_data = PacketData::Metadata(ChannelMetadata::parse(
+                    packet.packet_id,
+                    &packet.raw_data,
+                )?)
+            }
+            PacketKind::Unknown => return Err(PacketError::UnknownKind(frame.kind)),
+        }
+        Ok(packet)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    Frame(FrameError),
+    UnknownKind(u8),
+    InvalidPayload(String),
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Frame(e) => write!(f, "{}", e),
+            Self::UnknownKind(kind) => write!(f, "Received packet with unknown type {}", kind),
+            Self::InvalidPayload(reason) => write!(f, "Invalid packet data: {}", reason),
+        }
+    }
+}
+
+/// Why a port could not be opened, each holds the description given by the OS
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectError {
+    PermissionDenied(String),
+    Busy(String), // Another program has the port open
+    NotFound(String),
+    InvalidSettings(String),
+    Other(String),
+}
+
+impl From<tokio_serial::Error> for ConnectError {
+    fn from(e: tokio_serial::Error) -> Self {
+        use std::io::ErrorKind;
+        use tokio_serial::ErrorKind as SerialKind;
+        // EBUSY isn't given its own kind, only its description says what happened
+        if e.description.to_lowercase().contains("busy") {
+            return Self::Busy(e.description);
+        }
+        match e.kind {
+            SerialKind::Io(ErrorKind::PermissionDenied) => Self::PermissionDenied(e.description),
+            SerialKind::Io(ErrorKind::AddrInUse) => Self::Busy(e.description),
+            SerialKind::Io(ErrorKind::NotFound) | SerialKind::NoDevice => {
+                Self::NotFound(e.description)
+            }
+            SerialKind::InvalidInput => Self::InvalidSettings(e.description),
+            _ => Self::Other(e.description),
+        }
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PermissionDenied(e) => write!(
+                f,
+                "Permission denied, check you are allowed to use serial ports ({})",
+                e
+            ),
+            Self::Busy(e) => write!(f, "Port is in use by another program ({})", e),
+            Self::NotFound(e) => write!(f, "Port no longer exists, is it unplugged? ({})", e),
+            Self::InvalidSettings(e) => {
+                write!(f, "The port does not support these settings ({})", e)
+            }
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// The log is a device of its own so its channel can't clash with any the firmware sends
+pub fn log_device(device: &str) -> String {
+    format!("{} (log)", device)
+}
+
+/// The only channel of a log device
+const LOG_CHANNEL: u8 = 0;
+
+/// Lines longer than this aren't text, they're binary that happens to be printable
+const MAX_LINE_LEN: usize = 1024;
+
+/// Turns bytes read from serial into packets. Reads don't have to line up with packets, a chunk
+/// can hold part of a packet or several packets and the rest is kept until the next chunk.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    frames: FrameDecoder,
+    clock: DeviceClock,
+    text: Option<TextLines>, // Set in mixed mode
+}
+
+/// Collects printable text found between frames into lines
+#[derive(Debug, Default)]
+struct TextLines {
+    line: Vec<u8>,
+    binary: bool,    // The line so far has something unprintable in it
+    announced: bool, // The log channel has been described
+}
+
+impl TextLines {
+    /// Adds the bytes, returning any lines they finish. Lines with anything unprintable in them
+    /// are dropped.
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for byte in bytes {
+            match byte {
+                b'\n' => {
+                    if !self.binary && !self.line.is_empty() {
+                        lines.push(String::from_utf8_lossy(&self.line).into_owned());
+                    }
+                    self.line.clear();
+                    self.binary = false;
+                }
+                b'\r' => (),
+                b'\t' | 0x20..=0x7E if self.line.len() < MAX_LINE_LEN => self.line.push(*byte),
+                _ => self.binary = true,
+            }
+        }
+        lines
+    }
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// In mixed mode lines of printable text between frames, such as Serial.println
+    /// diagnostics, are sent as log packets instead of being reported as garbage
+    pub fn with_text_lines(mut self, split: bool) -> Self {
+        self.frames.keep_skipped(split);
+        self.text = split.then(TextLines::default);
+        self
+    }
+
+    /// Decodes every packet completed by the chunk, packets that could not be decoded are
+    /// returned as errors so they can be reported
+    pub fn decode(&mut self, chunk: &[u8]) -> Vec<Result<Packet, PacketError>> {
+        self.frames.push(chunk);
+        let mut packets = Vec::new();
+        while let Some(frame) = self.frames.next_frame() {
+            // Text skipped to find the frame came before it
+            self.split_text(&mut packets);
+            if self.text.is_some() && matches!(frame, Err(FrameError::Desync(_))) {
+                continue;
+            }
+            packets.push(frame.map_err(PacketError::Frame).and_then(|frame| {
+                let time = SampleTime {
+                    device: frame.device_time.map(|t| self.clock.extend(t)),
+                    ..SampleTime::now()
+                };
+                Packet::decode(frame, time)
+            }));
+        }
+        self.split_text(&mut packets);
+        packets
+    }
+
+    /// Turns the text skipped by the frame decoder into log packets, the log channel is
+    /// described before its first line
+    fn split_text(&mut self, packets: &mut Vec<Result<Packet, PacketError>>) {
+        let Some(text) = self.text.as_mut() else {
+            return;
+        };
+        for line in text.push(&self.frames.take_skipped()) {
+            if !text.announced {
+                text.announced = true;
+                let channel = ChannelDescriptor {
+                    id: LOG_CHANNEL,
+                    name: "Serial log".to_owned(),
+                    kind: PacketKind::String,
+                    unit: String::new(),
+                    range: None,
+                };
+                packets.push(Ok(Packet::log(PacketData::Channel(channel))));
+            }
+            let time = SampleTime::now();
+            let line = PacketData::String(line, LOG_CHANNEL, time);
+            packets.push(Ok(Packet::log(line)));
         }
     }
 }
 
 impl Arduino {
     /// Returns a completely empty Arduino class, ready for manipulation
-    pub fn new() -> Self {
+    pub fn new(device: String) -> Self {
         Self {
+            device,
             port: None,
             baud_rate: None,
-            serial_buffer: vec![0; 32],
+            serial_buffer: vec![0; 256],
+            decoder: PacketDecoder::new(),
+            connection: None,
         }
     }
 
-    /// Connects to the specified port with the given baud rate
-    /// Panics if the port cannot be opened
-    pub fn connect(&mut self, port_path: String, baud_rate: u32) {
-        let mut port = tokio_serial::new(port_path.clone(), baud_rate)
-            .timeout(Duration::from_millis(100))
-            .open_native_async()
-            .expect("Failed to open port");
+    /// Connects to the specified port with the given line settings. The port is opened
+    /// exclusively, nothing else can open it until this disconnects.
+    pub async fn connect(
+        &mut self,
+        port_path: String,
+        settings: &SerialSettings,
+    ) -> Result<(), ConnectError> {
+        let mut port = settings.builder(&port_path).open_native_async()?;
         #[cfg(unix)]
-        port.set_exclusive(false).expect(&format!(
-            "Failed to set port '{:?}' exclusivity to false",
-            port_path.clone()
-        ));
-        self.port = Some(Box::new(port));
-        self.baud_rate = Some(baud_rate);
+        port.set_exclusive(true)?;
+        if let Err(e) = port.write_data_terminal_ready(settings.dtr) {
+            eprintln!("{} {}", "Could not set DTR:".red(), e);
+        }
+        if settings.flow_control != FlowControl::Hardware {
+            if let Err(e) = port.write_request_to_send(settings.rts) {
+                eprintln!("{} {}", "Could not set RTS:".red(), e);
+            }
+        }
+        self.port = Some(port);
+        self.baud_rate = Some(settings.baud_rate);
+        // Whatever was left from the old port is only half a packet
+        self.decoder = PacketDecoder::new().with_text_lines(settings.text_lines);
+        let identity = list_ports()
+            .await
+            .iter()
+            .find(|p| p.port_name == port_path)
+            .and_then(PortIdentity::of);
+        self.connection = Some(Connection {
+            port_path,
+            settings: settings.clone(),
+            identity,
+        });
+        self.send_hello().await;
+        Ok(())
     }
 
-    /// Disconnects from the current port
+    /// Asks the device to describe itself and its channels. Boards that reset when the port is
+    /// opened may miss this, so firmware should also send its descriptor at startup.
+    pub async fn send_hello(&mut self) {
+        let hello = Frame::new(KIND_DESCRIPTOR, 0, vec![PROTOCOL_VERSION]).encode();
+        if let Some(port) = self.port.as_mut() {
+            if let Err(e) = port.write_all(&hello).await {
+                eprintln!("{} {}", "Could not send hello:".red(), e);
+            }
+        }
+    }
+
+    /// Disconnects from the current port, closing it
     pub fn disconnect(&mut self) {
         match self.port {
             Some(_) => {
                 self.port = None;
                 self.baud_rate = None;
+                self.decoder = PacketDecoder::new();
+                self.connection = None;
             }
             _ => {
                 eprintln!("Cannot disconnect: Arduino is not connected!");
@@ -129,140 +687,551 @@ impl Arduino {
         self.serial_buffer.resize(size, 0);
     }
 
-    pub fn read_loop(&mut self, rx: &mut mpsc::Receiver<ThreadMSG>, tx: mpsc::Sender<ThreadMSG>) {
-        match self.port {
-            Some(_) => {
-                loop {
-                    self.read_from_serial_packet(tx.clone());
-
-                    // Break if a disconnect message is sent
-                    match rx.try_recv() {
-                        Err(mpsc::error::TryRecvError::Empty) => {}
-                        Err(mpsc::error::TryRecvError::Disconnected) => break,
-                        Ok(t) => match t {
-                            ThreadMSG::Disconnect() => break,
-                            _ => {}
-                        },
-                    }
+    /// Sends a message to the GUI, tagged with this device. Waits if the GUI is behind so
+    /// messages are never reordered or dropped.
+    async fn send(&self, tx: &mpsc::Sender<DeviceMSG>, msg: ThreadMSG) {
+        Self::send_as(tx, self.device.clone(), msg).await;
+    }
+
+    /// Sends a message to the GUI tagged with another device, such as this device's log
+    async fn send_as(tx: &mpsc::Sender<DeviceMSG>, device: String, msg: ThreadMSG) {
+        if let Err(e) = tx.send((device, msg)).await {
+            eprintln!("{} {:?}", "Could not send to the GUI:".red(), e.0);
+        }
+    }
+
+    /// Reads from the port until a disconnect is asked for or the control channel is dropped,
+    /// reconnecting if the port is lost. Nothing is read while waiting for the port, so the task
+    /// sleeps rather than polling.
+    pub async fn read_loop(
+        &mut self,
+        rx: &mut mpsc::Receiver<ThreadMSG>,
+        tx: mpsc::Sender<DeviceMSG>,
+    ) {
+        if self.port.is_none() {
+            eprintln!("Arduino is not connected!");
+            return;
+        }
+        loop {
+            let Some(port) = self.port.as_mut() else {
+                break;
+            };
+            let read = tokio::select! {
+                msg = rx.recv() => match msg {
+                    None | Some(ThreadMSG::Disconnect(_)) => break,
+                    Some(_) => continue,
+                },
+                read = port.read(&mut self.serial_buffer) => read,
+            };
+            if let Err(e) = self.handle_read(read, &tx).await {
+                let port_path = self.port_path();
+                eprintln!("{} {}: {}", "Lost connection to".red(), port_path, e);
+                self.send(&tx, ThreadMSG::ConnectionLost((port_path, e.to_string())))
+                    .await;
+                if !self.reconnect(rx, &tx).await {
+                    break;
                 }
             }
-            _ => {
-                eprintln!("Arduino is not connected!");
+        }
+        self.disconnect();
+    }
+
+    fn port_path(&self) -> String {
+        match &self.connection {
+            Some(connection) => connection.port_path.clone(),
+            None => "Unknown port".to_owned(),
+        }
+    }
+
+    /// Keeps trying to reopen the lost port, backing off between attempts. Returns false if a
+    /// disconnect was asked for instead
+    async fn reconnect(
+        &mut self,
+        rx: &mut mpsc::Receiver<ThreadMSG>,
+        tx: &mpsc::Sender<DeviceMSG>,
+    ) -> bool {
+        let Some(connection) = self.connection.clone() else {
+            return false;
+        };
+        self.port = None;
+        let mut delay = RECONNECT_MIN_DELAY;
+        for attempt in 1.. {
+            self.send(
+                tx,
+                ThreadMSG::Reconnecting((connection.port_path.clone(), attempt)),
+            )
+            .await;
+            let retry = tokio::time::sleep(delay);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    _ = &mut retry => break,
+                    msg = rx.recv() => match msg {
+                        None | Some(ThreadMSG::Disconnect(_)) => return false,
+                        Some(_) => {}
+                    },
+                }
             }
+            let available = list_ports().await;
+            let found = ports::find_port(
+                &available,
+                &connection.port_path,
+                connection.identity.as_ref(),
+            );
+            if let Some(port_path) = found {
+                if self
+                    .connect(port_path.clone(), &connection.settings)
+                    .await
+                    .is_ok()
+                {
+                    // Keep looking for the same board if it gets renamed and lost again
+                    if let Some(new_connection) = self.connection.as_mut() {
+                        new_connection.identity = new_connection
+                            .identity
+                            .take()
+                            .or(connection.identity.clone());
+                    }
+                    self.send(tx, ThreadMSG::Reconnected(port_path)).await;
+                    return true;
+                }
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
         }
+        false
     }
 
     pub async fn flush_buffer(&mut self) {
         self.serial_buffer.clear();
     }
 
-    /// Reads from the serial data, determines the type from the first byte and then calls the
-    /// appropriate read function
-    pub fn read_from_serial_packet(&mut self, tx: mpsc::Sender<ThreadMSG>) {
-        let mut packet: Packet;
-        match self
-            .port
-            .as_mut()
-            .unwrap()
-            .read(self.serial_buffer.as_mut_slice())
-        {
-            Ok(_t) => {
-                let packet_kind: PacketKind = self.serial_buffer[0].into();
-                if packet_kind == PacketKind::Unknown {
-                    // Unknown packets are likely corrupt and
-                    // will cause a panic, so let's just return
-                    eprintln!("Packet Errror: Recevied packet with unknown type!");
-                    return;
-                };
-                let packet_id: u8 = self.serial_buffer[1];
-                let mut tmp_vec: Vec<u8> = vec![0; self.serial_buffer.len() - 3];
-                let mut j = 0;
-                for i in self.serial_buffer[2..].into_iter() {
-                    if *i == 0x17 || j == tmp_vec.len() {
-                        break;
+    /// Sends every packet completed by the bytes just read to the GUI. Errors if the port has
+    /// gone away.
+    async fn handle_read(
+        &mut self,
+        read: std::io::Result<usize>,
+        tx: &mpsc::Sender<DeviceMSG>,
+    ) -> Result<(), std::io::Error> {
+        match read {
+            // Nothing to read only happens once the device has gone
+            Ok(0) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(count) => {
+                let raw = self.serial_buffer[..count].to_vec();
+                self.send(tx, ThreadMSG::Raw((raw, SampleTime::now())))
+                    .await;
+                for packet in self.decoder.decode(&self.serial_buffer[..count]) {
+                    match packet {
+                        Ok(packet) if packet.is_log() => {
+                            let msg = ThreadMSG::Data(packet.constructed_data);
+                            Self::send_as(tx, log_device(&self.device), msg).await
+                        }
+                        Ok(packet) => {
+                            self.send(tx, ThreadMSG::Data(packet.constructed_data))
+                                .await
+                        }
+                        Err(e) => eprintln!("{} {}", "Packet Error:".red(), e),
                     }
-                    tmp_vec[j] = *i;
-                    j += 1;
                 }
-                tmp_vec.resize(j, 0);
-                packet = Packet::new(packet_kind, packet_id, tmp_vec);
-                match packet.packet_type {
-                    PacketKind::String => self.read_string_from_serial(&mut packet),
-                    PacketKind::PosInteger => self.read_integer_from_serial(false, &mut packet),
-                    PacketKind::NegInteger => self.read_integer_from_serial(true, &mut packet),
-                    PacketKind::Binary => (), // Not implemented, not sure if this is needed
-                    PacketKind::Float => self.read_float_from_serial(&mut packet),
-                    _ => unreachable!(),
-                }
-                crate::app::send_thread_msg(tx, ThreadMSG::Data(packet.constructed_data));
+                Ok(())
             }
-            Err(_e) => (), // xd
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock => Ok(()),
+                _ => Err(e),
+            },
         }
     }
+}
 
-    /// Read serial and convert the data to a utf-8 ASCII string
-    pub fn read_string_from_serial(&mut self, packet: &mut Packet) {
-        let mut tmp_string: String = "".to_owned();
-        for byte in (&packet.raw_data).into_iter() {
-            if *byte != 0 {
-                tmp_string.push(*byte as char);
-            }
+/// Lists the ports without blocking the task, listing can be slow on some systems
+async fn list_ports() -> Vec<tokio_serial::SerialPortInfo> {
+    tokio::task::spawn_blocking(tokio_serial::available_ports)
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::DeviceTime;
+
+    /// Three packets of different types, one after the other
+    fn sample_stream() -> Vec<u8> {
+        let mut stream = Frame::new(1, 0, b"Reading...".to_vec()).encode();
+        stream.extend(Frame::new(2, 1, vec![0x17, 0x02]).encode());
+        stream.extend(Frame::new(5, 2, b"4.25000".to_vec()).encode());
+        stream
+    }
+
+    fn describe(data: &PacketData) -> String {
+        match data {
+            PacketData::String(d, id, _) => format!("{} {} {}", data.display_variant(), id, d),
+            PacketData::Integer(d, id, _) => format!("{} {} {}", data.display_variant(), id, d),
+            PacketData::Float(d, id, _) => format!("{} {} {}", data.display_variant(), id, d),
+            PacketData::Binary(d, id, _) => format!("{} {} {:?}", data.display_variant(), id, d),
+            PacketData::Vector(d, id, _) => format!("{} {} {:?}", data.display_variant(), id, d),
+            PacketData::Descriptor(d) => format!("{} {:?}", data.display_variant(), d),
+            PacketData::Channel(d) => format!("{} {:?}", data.display_variant(), d),
+            PacketData::Metadata(d) => format!("{} {:?}", data.display_variant(), d),
+            PacketData::Gap(id, _) => format!("{} {}", data.display_variant(), id),
+            PacketData::None() => data.display_variant().to_owned(),
         }
-        packet.constructed_data = PacketData::String(tmp_string, packet.packet_id, Instant::now());
     }
 
-    /// Reads serial, converts tp a string and then parses to float
-    pub fn read_float_from_serial(&mut self, packet: &mut Packet) {
-        let mut tmp_string: String = "".to_owned();
-        for byte in (&packet.raw_data).into_iter() {
-            if *byte != 0 {
-                tmp_string.push(*byte as char);
+    fn expected() -> Vec<String> {
+        vec![
+            "String 0 Reading...".to_owned(),
+            "Integer 1 535".to_owned(),
+            "Float 2 4.25".to_owned(),
+        ]
+    }
+
+    /// Feeds the chunks through a single decoder, panics on any error
+    fn decode_chunks<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> Vec<String> {
+        let mut decoder = PacketDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            for packet in decoder.decode(chunk) {
+                decoded.push(describe(packet.expect("Packet failed to decode").data()));
             }
         }
-        match tmp_string.parse::<f64>() {
-            Err(_) => println!("{:?}", &packet.raw_data),
-            Ok(float_value) => {
-                packet.constructed_data =
-                    PacketData::Float(float_value, packet.packet_id, Instant::now());
+        decoded
+    }
+
+    /// Small xorshift so the split points change between runs of the loop without pulling in a
+    /// random number crate
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let stream = sample_stream();
+        assert_eq!(decode_chunks(stream.chunks(1)), expected());
+    }
+
+    #[test]
+    fn decodes_concatenated_frames() {
+        let stream = sample_stream();
+        assert_eq!(
+            decode_chunks(std::iter::once(stream.as_slice())),
+            expected()
+        );
+    }
+
+    #[test]
+    fn decodes_random_splits() {
+        let stream = sample_stream();
+        for seed in 1..=200 {
+            let mut rng = XorShift(seed);
+            let mut chunks = Vec::new();
+            let mut rest = stream.as_slice();
+            while !rest.is_empty() {
+                let size = (rng.next() as usize % 16 + 1).min(rest.len());
+                let (chunk, remaining) = rest.split_at(size);
+                chunks.push(chunk);
+                rest = remaining;
             }
+            assert_eq!(
+                decode_chunks(chunks.into_iter()),
+                expected(),
+                "seed {}",
+                seed
+            );
         }
     }
 
-    /// Reads serial and converts the data to an integer, boolean determines
-    /// if the integer is positive or negative
-    pub fn read_integer_from_serial(&mut self, is_negative: bool, packet: &mut Packet) {
-        let mut tmp = 0;
-        let max_bytes = isize::BITS / 8;
-        for (i, byte) in (&packet.raw_data).into_iter().enumerate() {
-            let mut tmp_byte = byte.clone() as i16;
-            if is_negative {
-                if tmp_byte == 0 {
-                    continue;
-                }
-                tmp_byte -= 0xFF;
-                if i == 0 {
-                    // Two's compliment ?
-                    tmp_byte -= 1;
-                }
+    #[test]
+    fn partial_frame_waits_for_the_rest() {
+        let stream = Frame::new(1, 3, b"Hello".to_vec()).encode();
+        let mut decoder = PacketDecoder::new();
+        assert!(decoder.decode(&stream[..stream.len() - 1]).is_empty());
+        let packets = decoder.decode(&stream[stream.len() - 1..]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            describe(packets[0].as_ref().unwrap().data()),
+            "String 3 Hello"
+        );
+    }
+
+    #[test]
+    fn reports_garbage_and_resyncs() {
+        let mut stream = b"BLE scan".to_vec();
+        stream.extend(sample_stream());
+        let mut decoder = PacketDecoder::new();
+        let packets = decoder.decode(&stream);
+        assert_eq!(
+            packets[0].as_ref().err(),
+            Some(&PacketError::Frame(FrameError::Desync(8))),
+            "Garbage should be reported"
+        );
+        let decoded: Vec<String> = packets[1..]
+            .iter()
+            .map(|p| describe(p.as_ref().unwrap().data()))
+            .collect();
+        assert_eq!(decoded, expected());
+    }
+
+    #[test]
+    fn reports_corrupt_frame_and_keeps_going() {
+        let mut stream = sample_stream();
+        // Flip a bit in the payload of the first frame
+        stream[8] ^= 0x01;
+        let mut decoder = PacketDecoder::new();
+        let packets = decoder.decode(&stream);
+        assert!(matches!(
+            packets[0],
+            Err(PacketError::Frame(FrameError::BadChecksum { .. }))
+        ));
+        let decoded: Vec<String> = packets[1..]
+            .iter()
+            .map(|p| describe(p.as_ref().unwrap().data()))
+            .collect();
+        assert_eq!(decoded, expected()[1..]);
+    }
+
+    #[test]
+    fn splits_text_lines_from_frames() {
+        let mut stream = b"BLE scan for a peripheral...\r\n".to_vec();
+        stream.extend(sample_stream());
+        stream.extend(b"\x01\x02 not text\nDone\r\n");
+        let mut decoder = PacketDecoder::new().with_text_lines(true);
+        // Split up so lines & frames are cut across reads
+        let decode = |decoder: &mut PacketDecoder, stream: &[u8]| -> Vec<String> {
+            stream
+                .chunks(5)
+                .flat_map(|chunk| decoder.decode(chunk))
+                .map(|p| match p {
+                    Err(PacketError::Frame(FrameError::BadChecksum { .. })) => {
+                        "Bad checksum".to_owned()
+                    }
+                    Err(e) => panic!("{}", e),
+                    // Log packets can share an ID with the firmware's, they go to another device
+                    Ok(p) => match (p.is_log(), p.data()) {
+                        (true, PacketData::Channel(c)) => format!("Log channel {}", c.name),
+                        (true, data) => format!("Log {}", describe(data)),
+                        (false, data) => describe(data),
+                    },
+                })
+                .collect()
+        };
+        let mut expected_packets = vec![
+            "Log channel Serial log".to_owned(),
+            "Log String 0 BLE scan for a peripheral...".to_owned(),
+        ];
+        expected_packets.extend(expected());
+        expected_packets.push("Log String 0 Done".to_owned());
+        assert_eq!(decode(&mut decoder, &stream), expected_packets);
+
+        // A corrupt frame between lines is reported, the lines either side are still split
+        // where they were printed
+        let mut stream = b"Retrying\r\n".to_vec();
+        let mut corrupt = sample_stream();
+        corrupt[8] ^= 0x01;
+        stream.extend(&corrupt);
+        stream.extend(b"\r\nDone\r\n");
+        let mut expected_packets = vec![
+            "Log String 0 Retrying".to_owned(),
+            "Bad checksum".to_owned(),
+        ];
+        expected_packets.extend_from_slice(&expected()[1..]);
+        expected_packets.push("Log String 0 Done".to_owned());
+        assert_eq!(decode(&mut decoder, &stream), expected_packets);
+    }
+
+    fn decode_single(frame: Frame) -> Result<Packet, PacketError> {
+        let mut packets = PacketDecoder::new().decode(&frame.encode());
+        assert_eq!(packets.len(), 1);
+        packets.remove(0)
+    }
+
+    #[test]
+    fn decodes_native_floats() {
+        let packet = decode_single(Frame::new(6, 4, (-1.5f32).to_le_bytes().to_vec())).unwrap();
+        assert_eq!(describe(packet.data()), "Float 4 -1.5");
+        let packet = decode_single(Frame::new(7, 4, 0.1f64.to_le_bytes().to_vec())).unwrap();
+        assert_eq!(describe(packet.data()), "Float 4 0.1");
+    }
+
+    #[test]
+    fn rejects_bad_floats() {
+        assert!(matches!(
+            decode_single(Frame::new(6, 4, vec![0, 0])),
+            Err(PacketError::InvalidPayload(_))
+        ));
+        assert!(matches!(
+            decode_single(Frame::new(5, 4, b"nope".to_vec())),
+            Err(PacketError::InvalidPayload(_))
+        ));
+    }
+
+    /// Mirrors PacketHandler::send in the sketches, legacy chooses between the old signed kinds
+    /// (2 & 3, int16_t only) and the fixed width kinds
+    fn encode_integer(kind: u8, value: i128, size: usize, legacy: bool) -> Frame {
+        let kind = match legacy {
+            true if value < 0 => 3,
+            true => 2,
+            false => kind,
+        };
+        Frame::new(kind, 9, value.to_le_bytes()[..size].to_vec())
+    }
+
+    #[test]
+    fn integers_round_trip_every_width() {
+        let widths: [(u8, i128, i128, usize); 8] = [
+            (8, u8::MIN as i128, u8::MAX as i128, 1),
+            (9, i8::MIN as i128, i8::MAX as i128, 1),
+            (10, u16::MIN as i128, u16::MAX as i128, 2),
+            (11, i16::MIN as i128, i16::MAX as i128, 2),
+            (12, u32::MIN as i128, u32::MAX as i128, 4),
+            (13, i32::MIN as i128, i32::MAX as i128, 4),
+            (14, u64::MIN as i128, u64::MAX as i128, 8),
+            (15, i64::MIN as i128, i64::MAX as i128, 8),
+        ];
+        let mut rng = XorShift(0x5EED);
+        for (kind, min, max, size) in widths {
+            let mut values = vec![min, max, 0, 1, min + 1, max - 1];
+            values.extend([-1, -128, -129, -256, -257].iter().filter(|v| **v >= min));
+            for _ in 0..1000 {
+                // Two draws so 64 bit widths get values from the whole range
+                let random = (rng.next() as i128) << 32 | rng.next() as i128;
+                values.push(min + random.rem_euclid(max - min + 1));
             }
-            if i == max_bytes
-                .try_into()
-                .expect("Packet integer exceeds the integer limit!")
-            {
-                tmp += (tmp_byte as isize) << ((i * 8) - 1);
-            } else if i > max_bytes
-                .try_into()
-                .expect("Packet integer exceeds the integer limit!")
-            {
-                eprintln!("Integer exceeds the integer limit, stopping!");
-                break;
-            } else {
-                tmp += (tmp_byte as isize) << i * 8;
+            for value in values {
+                let packet = decode_single(encode_integer(kind, value, size, false)).unwrap();
+                assert!(
+                    matches!(packet.data(), PacketData::Integer(v, 9, _) if *v == value),
+                    "kind {} value {} decoded as {:?}",
+                    kind,
+                    value,
+                    packet.data()
+                );
             }
         }
-        packet.constructed_data = PacketData::Integer(tmp, packet.packet_id, Instant::now());
     }
 
-    /// Reads the raw binary from serial
-    pub async fn read_binary_from_serial(&mut self) {}
+    #[test]
+    fn legacy_integers_round_trip() {
+        let mut values: Vec<i128> = vec![0, -1, -256, -5325, 500, -500, 255, -255];
+        values.extend((i16::MIN..=i16::MAX).step_by(97).map(|v| v as i128));
+        values.extend([i16::MIN as i128, i16::MAX as i128]);
+        for value in values {
+            let packet = decode_single(encode_integer(0, value, 2, true)).unwrap();
+            assert!(
+                matches!(packet.data(), PacketData::Integer(v, 9, _) if *v == value),
+                "value {} decoded as {:?}",
+                value,
+                packet.data()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_integer_width() {
+        assert!(matches!(
+            decode_single(Frame::new(13, 0, vec![1, 2])),
+            Err(PacketError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_vectors() {
+        let mut payload = vec![11];
+        payload.extend((-3i16).to_le_bytes());
+        payload.push(6);
+        payload.extend(2.5f32.to_le_bytes());
+        payload.push(8);
+        payload.push(200);
+        let packet = decode_single(Frame::new(16, 3, payload.clone())).unwrap();
+        assert!(matches!(
+            packet.data(),
+            PacketData::Vector(v, 3, _) if *v == vec![
+                FieldValue::Signed(-3),
+                FieldValue::Float(2.5),
+                FieldValue::Unsigned(200)
+            ]
+        ));
+
+        // Cut short in the middle of the float
+        assert!(matches!(
+            decode_single(Frame::new(16, 3, payload[..5].to_vec())),
+            Err(PacketError::InvalidPayload(_))
+        ));
+        // Strings can't be part of a vector
+        assert!(matches!(
+            decode_single(Frame::new(16, 3, vec![1, 65])),
+            Err(PacketError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn carries_device_time() {
+        let mut decoder = PacketDecoder::new();
+        let mut stream = Frame::new(6, 1, 1.0f32.to_le_bytes().to_vec())
+            .with_device_time(DeviceTime::Micros(u32::MAX - 9))
+            .encode();
+        stream.extend(
+            Frame::new(6, 1, 2.0f32.to_le_bytes().to_vec())
+                .with_device_time(DeviceTime::Micros(10))
+                .encode(),
+        );
+        stream.extend(Frame::new(6, 1, 3.0f32.to_le_bytes().to_vec()).encode());
+        let times: Vec<Option<Duration>> = decoder
+            .decode(&stream)
+            .iter()
+            .map(|p| p.as_ref().unwrap().data().time().unwrap().device)
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                Some(Duration::from_micros(u32::MAX as u64 - 9)),
+                Some(Duration::from_micros((1 << 32) + 10)),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_kind() {
+        let stream = Frame::new(50, 0, vec![1]).encode();
+        let mut decoder = PacketDecoder::new();
+        let packets = decoder.decode(&stream);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].as_ref().err(),
+            Some(&PacketError::UnknownKind(50))
+        );
+    }
+
+    #[test]
+    fn classifies_connect_errors() {
+        use tokio_serial::{Error, ErrorKind};
+        let error = |kind, description| ConnectError::from(Error::new(kind, description));
+        assert_eq!(
+            error(
+                ErrorKind::Io(std::io::ErrorKind::PermissionDenied),
+                "Permission denied"
+            ),
+            ConnectError::PermissionDenied("Permission denied".to_owned())
+        );
+        assert_eq!(
+            error(ErrorKind::Unknown, "Device or resource busy"),
+            ConnectError::Busy("Device or resource busy".to_owned())
+        );
+        assert_eq!(
+            error(ErrorKind::NoDevice, "No such device"),
+            ConnectError::NotFound("No such device".to_owned())
+        );
+        assert_eq!(
+            error(ErrorKind::InvalidInput, "Invalid baud rate"),
+            ConnectError::InvalidSettings("Invalid baud rate".to_owned())
+        );
+    }
 }
@@ -0,0 +1,579 @@
+/*
+ *  Capture files
+ *
+ *  A recording of everything received from every device, written as it arrives so a crash or
+ *  power cut only loses what hasn't been flushed yet (at most a second). The file is append
+ *  only, it's a header followed by records:
+ *
+ *  Header: | "ACGCAP" | version |
+ *  Record: | event | body len (u32) | body ... | crc16 (of everything before it) |
+ *  Body:   | device len (u16) | device ... | wall time (i64 us since 1970) | has device time |
+ *          | device time (u64 us, only if it has one) | event data ... |
+ *  Data:   | type | packet ID | value ... |, except a device descriptor which has no ID:
+ *          | type | protocol version | name | firmware version |
+ *
+ *  A record cut short or with a bad checksum can only be the last one written before a crash,
+ *  reading stops there and everything before it is kept. All numbers are little-endian.
+ */
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::arduino::{PacketData, PacketKind};
+use crate::clock::SampleTime;
+use crate::device::{ChannelDescriptor, ChannelMetadata, DeviceDescriptor, Scaling};
+use crate::frame::crc16;
+use crate::layout::FieldValue;
+
+pub const MAGIC: &[u8; 6] = b"ACGCAP";
+pub const VERSION: u8 = 1;
+pub const EXTENSION: &str = "acap";
+
+/// How often the recording is flushed to disk
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const EVENT_DATA: u8 = 1;
+const EVENT_RAW: u8 = 2;
+const EVENT_LOST: u8 = 3;
+
+// Tags for the type of data in a data event
+const DATA_INTEGER: u8 = 1;
+const DATA_FLOAT: u8 = 2;
+const DATA_STRING: u8 = 3;
+const DATA_BINARY: u8 = 4;
+const DATA_VECTOR: u8 = 5;
+const DATA_GAP: u8 = 6;
+const DATA_DESCRIPTOR: u8 = 7;
+const DATA_CHANNEL: u8 = 8;
+const DATA_METADATA: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureEvent {
+    Data(PacketData),
+    Raw(Vec<u8>), // Bytes exactly as they were read from the port
+    Lost,         // The connection was lost
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub device: String,
+    pub time: SampleTime, // Only the wall & device times are saved, host is when it was read
+    pub event: CaptureEvent,
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(std::io::Error),
+    NotACapture,
+    UnsupportedVersion(u8),
+}
+
+impl Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::NotACapture => write!(f, "Not a capture file"),
+            Self::UnsupportedVersion(v) => write!(f, "Capture version {} is not supported", v),
+        }
+    }
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A capture that has been read back
+#[derive(Debug)]
+pub struct Capture {
+    pub records: Vec<CaptureRecord>,
+    pub truncated: bool, // The last record was cut short or corrupt and has been left out
+}
+
+/// Appends records to a new capture file
+#[derive(Debug)]
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl CaptureWriter {
+    /// Creates the file, an existing file is never overwritten
+    pub fn create(path: &Path) -> Result<Self, CaptureError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            path: path.to_owned(),
+        };
+        writer.file.write_all(MAGIC)?;
+        writer.file.write_all(&[VERSION])?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    pub fn write(
+        &mut self,
+        device: &str,
+        time: &SampleTime,
+        event: &CaptureEvent,
+    ) -> Result<(), CaptureError> {
+        let mut body = Vec::new();
+        put_string16(&mut body, device);
+        body.extend(wall_micros(time.wall).to_le_bytes());
+        match time.device {
+            Some(device_time) => {
+                body.push(1);
+                body.extend((device_time.as_micros() as u64).to_le_bytes());
+            }
+            None => body.push(0),
+        }
+        let kind = match event {
+            CaptureEvent::Data(data) => {
+                encode_data(&mut body, data);
+                EVENT_DATA
+            }
+            CaptureEvent::Raw(bytes) => {
+                put_bytes32(&mut body, bytes);
+                EVENT_RAW
+            }
+            CaptureEvent::Lost => EVENT_LOST,
+        };
+        let mut record = vec![kind];
+        record.extend((body.len() as u32).to_le_bytes());
+        record.extend(body);
+        record.extend(crc16(&record).to_le_bytes());
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+
+    /// Writes everything buffered and waits for it to reach the disk
+    pub fn flush(&mut self) -> Result<(), CaptureError> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Reads a whole capture, a damaged last record is skipped rather than failing the whole file
+pub fn read_capture(path: &Path) -> Result<Capture, CaptureError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    parse_capture(&bytes)
+}
+
+pub fn parse_capture(bytes: &[u8]) -> Result<Capture, CaptureError> {
+    if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(CaptureError::NotACapture);
+    }
+    if bytes[MAGIC.len()] != VERSION {
+        return Err(CaptureError::UnsupportedVersion(bytes[MAGIC.len()]));
+    }
+    let mut rest = &bytes[MAGIC.len() + 1..];
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        let Some((record, remaining)) = next_record(rest) else {
+            return Ok(Capture {
+                records,
+                truncated: true,
+            });
+        };
+        records.extend(record);
+        rest = remaining;
+    }
+    Ok(Capture {
+        records,
+        truncated: false,
+    })
+}
+
+/// Splits the first record off, None if it's cut short or corrupt. Records with an event this
+/// version doesn't know are skipped.
+fn next_record(bytes: &[u8]) -> Option<(Option<CaptureRecord>, &[u8])> {
+    let len = u32::from_le_bytes(bytes.get(1..5)?.try_into().ok()?) as usize;
+    let end = 5 + len;
+    let crc = u16::from_le_bytes(bytes.get(end..end + 2)?.try_into().ok()?);
+    if crc16(&bytes[..end]) != crc {
+        return None;
+    }
+    let mut reader = Reader(&bytes[5..end]);
+    let device = reader.string16()?;
+    let wall = UNIX_EPOCH + Duration::from_micros(reader.i64()?.max(0) as u64);
+    let device_time = match reader.u8()? {
+        0 => None,
+        _ => Some(Duration::from_micros(reader.u64()?)),
+    };
+    let time = SampleTime {
+        wall,
+        device: device_time,
+        ..SampleTime::now()
+    };
+    let event = match bytes[0] {
+        EVENT_DATA => Some(CaptureEvent::Data(decode_data(&mut reader, time)?)),
+        EVENT_RAW => Some(CaptureEvent::Raw(reader.bytes32()?.to_vec())),
+        EVENT_LOST => Some(CaptureEvent::Lost),
+        _ => None,
+    };
+    let record = event.map(|event| CaptureRecord {
+        device,
+        time,
+        event,
+    });
+    Some((record, &bytes[end + 2..]))
+}
+
+fn wall_micros(wall: SystemTime) -> i64 {
+    match wall.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+fn put_string16(out: &mut Vec<u8>, text: &str) {
+    let bytes = &text.as_bytes()[..text.len().min(u16::MAX as usize)];
+    out.extend((bytes.len() as u16).to_le_bytes());
+    out.extend(bytes);
+}
+
+fn put_bytes32(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+fn put_scaling(out: &mut Vec<u8>, name: &str, scaling: &Scaling) {
+    out.extend(scaling.scale.to_le_bytes());
+    out.extend(scaling.offset.to_le_bytes());
+    put_string16(out, name);
+    put_string16(out, &scaling.unit);
+}
+
+fn encode_data(out: &mut Vec<u8>, data: &PacketData) {
+    match data {
+        PacketData::Integer(d, id, _) => {
+            out.extend([DATA_INTEGER, *id]);
+            out.extend(d.to_le_bytes());
+        }
+        PacketData::Float(d, id, _) => {
+            out.extend([DATA_FLOAT, *id]);
+            out.extend(d.to_le_bytes());
+        }
+        PacketData::String(d, id, _) => {
+            out.extend([DATA_STRING, *id]);
+            put_bytes32(out, d.as_bytes());
+        }
+        PacketData::Binary(d, id, _) => {
+            out.extend([DATA_BINARY, *id]);
+            put_bytes32(out, d);
+        }
+        PacketData::Vector(d, id, _) => {
+            out.extend([DATA_VECTOR, *id]);
+            out.extend((d.len() as u16).to_le_bytes());
+            for value in d {
+                match value {
+                    FieldValue::Unsigned(v) => {
+                        out.push(0);
+                        out.extend(v.to_le_bytes());
+                    }
+                    FieldValue::Signed(v) => {
+                        out.push(1);
+                        out.extend(v.to_le_bytes());
+                    }
+                    FieldValue::Float(v) => {
+                        out.push(2);
+                        out.extend(v.to_le_bytes());
+                    }
+                }
+            }
+        }
+        PacketData::Gap(id, _) => out.extend([DATA_GAP, *id]),
+        PacketData::Descriptor(d) => {
+            out.push(DATA_DESCRIPTOR);
+            out.push(d.protocol_version);
+            put_string16(out, &d.name);
+            put_string16(out, &d.firmware_version);
+        }
+        PacketData::Channel(d) => {
+            out.extend([DATA_CHANNEL, d.id, d.kind.code()]);
+            let (min, max) = d.range.unwrap_or((f64::NAN, f64::NAN));
+            out.extend(min.to_le_bytes());
+            out.extend(max.to_le_bytes());
+            put_string16(out, &d.name);
+            put_string16(out, &d.unit);
+        }
+        PacketData::Metadata(d) => {
+            out.extend([DATA_METADATA, d.id]);
+            put_scaling(out, &d.name, &d.scaling);
+        }
+        PacketData::None() => {}
+    }
+}
+
+fn decode_data(reader: &mut Reader<'_>, time: SampleTime) -> Option<PacketData> {
+    let tag = reader.u8()?;
+    if tag == DATA_DESCRIPTOR {
+        return Some(PacketData::Descriptor(DeviceDescriptor {
+            protocol_version: reader.u8()?,
+            name: reader.string16()?,
+            firmware_version: reader.string16()?,
+        }));
+    }
+    let id = reader.u8()?;
+    Some(match tag {
+        DATA_INTEGER => PacketData::Integer(i128::from_le_bytes(reader.array()?), id, time),
+        DATA_FLOAT => PacketData::Float(f64::from_le_bytes(reader.array()?), id, time),
+        DATA_STRING => PacketData::String(
+            String::from_utf8_lossy(reader.bytes32()?).into_owned(),
+            id,
+            time,
+        ),
+        DATA_BINARY => PacketData::Binary(reader.bytes32()?.to_vec(), id, time),
+        DATA_VECTOR => {
+            let count = u16::from_le_bytes(reader.array()?);
+            let mut values = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let tag = reader.u8()?;
+                let bytes = reader.array()?;
+                values.push(match tag {
+                    0 => FieldValue::Unsigned(u64::from_le_bytes(bytes)),
+                    1 => FieldValue::Signed(i64::from_le_bytes(bytes)),
+                    _ => FieldValue::Float(f64::from_le_bytes(bytes)),
+                });
+            }
+            PacketData::Vector(values, id, time)
+        }
+        DATA_GAP => PacketData::Gap(id, time),
+        DATA_CHANNEL => {
+            let kind = PacketKind::from(reader.u8()?);
+            let min = f64::from_le_bytes(reader.array()?);
+            let max = f64::from_le_bytes(reader.array()?);
+            PacketData::Channel(ChannelDescriptor {
+                id,
+                kind,
+                range: match min.is_nan() || max.is_nan() {
+                    true => None,
+                    false => Some((min, max)),
+                },
+                name: reader.string16()?,
+                unit: reader.string16()?,
+            })
+        }
+        DATA_METADATA => {
+            let scale = f64::from_le_bytes(reader.array()?);
+            let offset = f64::from_le_bytes(reader.array()?);
+            let name = reader.string16()?;
+            PacketData::Metadata(ChannelMetadata {
+                id,
+                name,
+                scaling: Scaling {
+                    unit: reader.string16()?,
+                    scale,
+                    offset,
+                },
+            })
+        }
+        _ => return None,
+    })
+}
+
+/// Reads values from the front of a record's body
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.array()?))
+    }
+
+    fn string16(&mut self) -> Option<String> {
+        let len = u16::from_le_bytes(self.array()?) as usize;
+        Some(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn bytes32(&mut self) -> Option<&'a [u8]> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        self.take(len)
+    }
+}
+
+/// A new file name for a recording started now, e.g. "capture-2024-03-01_14-05-09.acap"
+pub fn new_capture_name() -> String {
+    let now = crate::clock::format_utc(crate::clock::unix_secs(SystemTime::now()));
+    format!(
+        "capture-{}.{}",
+        now[..19].replace(' ', "_").replace(':', "-"),
+        EXTENSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("acg-{}-{}.{}", name, std::process::id(), EXTENSION));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// The event with every host time set to `host`, host times can't survive a round trip
+    fn normalised(event: &CaptureEvent, host: Instant) -> CaptureEvent {
+        let mut event = event.clone();
+        if let CaptureEvent::Data(data) = &mut event {
+            if let Some(time) = data.time_mut() {
+                time.host = host;
+            }
+        }
+        event
+    }
+
+    fn assert_same(read: &[CaptureRecord], written: &[CaptureRecord]) {
+        assert_eq!(read.len(), written.len());
+        let host = Instant::now();
+        for (read, written) in read.iter().zip(written) {
+            assert_eq!(read.device, written.device);
+            assert_eq!(wall_micros(read.time.wall), wall_micros(written.time.wall));
+            assert_eq!(read.time.device, written.time.device);
+            assert_eq!(
+                normalised(&read.event, host),
+                normalised(&written.event, host)
+            );
+        }
+    }
+
+    fn sample_records() -> Vec<CaptureRecord> {
+        let time = SampleTime {
+            wall: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            device: Some(Duration::from_micros(5_000_001)),
+            ..SampleTime::now()
+        };
+        let record = |event| CaptureRecord {
+            device: "/dev/ttyACM0".to_owned(),
+            time,
+            event,
+        };
+        vec![
+            record(CaptureEvent::Raw(vec![0xA5, 0x5A, 1])),
+            record(CaptureEvent::Data(PacketData::Integer(-5325, 1, time))),
+            record(CaptureEvent::Data(PacketData::Float(4.25, 2, time))),
+            record(CaptureEvent::Data(PacketData::String(
+                "Reading...".to_owned(),
+                0,
+                time,
+            ))),
+            record(CaptureEvent::Data(PacketData::Vector(
+                vec![
+                    FieldValue::Signed(-3),
+                    FieldValue::Float(2.5),
+                    FieldValue::Unsigned(200),
+                ],
+                3,
+                time,
+            ))),
+            record(CaptureEvent::Data(PacketData::Descriptor(
+                DeviceDescriptor {
+                    name: "Crane".to_owned(),
+                    firmware_version: "1.2.0".to_owned(),
+                    protocol_version: 3,
+                },
+            ))),
+            record(CaptureEvent::Data(PacketData::Channel(ChannelDescriptor {
+                id: 2,
+                name: "Load".to_owned(),
+                kind: PacketKind::Float32,
+                unit: "N".to_owned(),
+                range: Some((0.0, 40.0)),
+            }))),
+            record(CaptureEvent::Data(PacketData::Metadata(ChannelMetadata {
+                id: 2,
+                name: "Load".to_owned(),
+                scaling: Scaling {
+                    unit: "kN".to_owned(),
+                    scale: 0.001,
+                    offset: 0.0,
+                },
+            }))),
+            record(CaptureEvent::Lost),
+        ]
+    }
+
+    fn write_records(path: &Path, records: &[CaptureRecord]) {
+        let mut writer = CaptureWriter::create(path).unwrap();
+        for record in records {
+            writer
+                .write(&record.device, &record.time, &record.event)
+                .unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let path = temp_path("round-trip");
+        let records = sample_records();
+        write_records(&path, &records);
+        let capture = read_capture(&path).unwrap();
+        assert!(!capture.truncated);
+        assert_same(&capture.records, &records);
+        // Never overwrites an existing capture
+        assert!(CaptureWriter::create(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_records_before_a_torn_write() {
+        let path = temp_path("torn");
+        let records = sample_records();
+        write_records(&path, &records);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Cut part way through the last record, then corrupt it instead
+        let capture = parse_capture(&bytes[..bytes.len() - 3]).unwrap();
+        assert!(capture.truncated);
+        assert_eq!(capture.records.len(), records.len() - 1);
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 3;
+        corrupt[last] ^= 0xFF;
+        let capture = parse_capture(&corrupt).unwrap();
+        assert!(capture.truncated);
+        assert_eq!(capture.records.len(), records.len() - 1);
+
+        assert!(matches!(
+            parse_capture(b"not a capture"),
+            Err(CaptureError::NotACapture)
+        ));
+    }
+}
@@ -0,0 +1,373 @@
+/*
+ *  Clocks
+ *
+ *  Samples are timestamped by the PC when they are decoded, which includes any delay from the
+ *  radio relay and serial buffering. Packets may also carry the Arduino's own millis() or
+ *  micros(), this module keeps both and maps the Arduino's clock onto the PC's clock.
+ */
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::frame::DeviceTime;
+
+/// When a sample was taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleTime {
+    /// When the PC decoded the sample
+    pub host: Instant,
+    /// The same moment as `host` on the PC's wall clock, unlike `Instant` this can be saved and
+    /// compared between runs
+    pub wall: SystemTime,
+    /// Time since the Arduino started, if the packet carried it
+    pub device: Option<Duration>,
+}
+
+impl SampleTime {
+    pub fn now() -> Self {
+        Self {
+            host: Instant::now(),
+            wall: SystemTime::now(),
+            device: None,
+        }
+    }
+
+    /// Time since the PC decoded the sample
+    pub fn elapsed(&self) -> Duration {
+        self.host.elapsed()
+    }
+
+    /// The same moment moved later by `seconds`, or earlier if it's negative. The device time
+    /// is left alone.
+    pub fn shifted(&self, seconds: f64) -> Self {
+        let offset = Duration::from_secs_f64(seconds.abs());
+        match seconds >= 0.0 {
+            true => Self {
+                host: self.host + offset,
+                wall: self.wall + offset,
+                ..*self
+            },
+            false => Self {
+                // Can't go back past when the PC started
+                host: self.host.checked_sub(offset).unwrap_or(self.host),
+                wall: self.wall - offset,
+                ..*self
+            },
+        }
+    }
+
+    /// A sample taken now, but `seconds` after 1970 by the wall clock, so tests can check times
+    #[cfg(test)]
+    pub fn at_wall(seconds: f64) -> Self {
+        Self {
+            wall: UNIX_EPOCH + Duration::from_secs_f64(seconds),
+            ..Self::now()
+        }
+    }
+
+    /// Converts another instant to wall clock time, using this sample as the reference point
+    pub fn wall_at(&self, host: Instant) -> SystemTime {
+        match host.checked_duration_since(self.host) {
+            Some(later) => self.wall + later,
+            None => self.wall - self.host.duration_since(host),
+        }
+    }
+}
+
+/// Seconds since 1970-01-01 UTC, negative for times before it
+pub fn unix_secs(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+/// Formats seconds since the unix epoch as "YYYY-MM-DD HH:MM:SS.mmm" in UTC
+pub fn format_utc(unix_secs: f64) -> String {
+    let millis = (unix_secs * 1000.0).round() as i64;
+    let (year, month, day) = civil_from_days(millis.div_euclid(86_400_000));
+    let ms_of_day = millis.rem_euclid(86_400_000);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    )
+}
+
+/// Reads "YYYY-MM-DD HH:MM:SS.fff" (or with a 'T' & trailing 'Z') as UTC, or just "HH:MM:SS.fff"
+/// as seconds since midnight. The fraction is optional, returns seconds since the unix epoch.
+pub fn parse_utc(text: &str) -> Option<f64> {
+    let text = text.trim().trim_end_matches('Z');
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (Some(date), time),
+        None if text.contains(':') => (None, text),
+        None => (Some(text), "00:00:00"),
+    };
+    let days = match date {
+        Some(date) => {
+            let mut parts = date.split('-').map(|p| p.parse::<i64>().ok());
+            let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+            if parts.next().is_some()
+                || !(0..=9999).contains(&year)
+                || !(1..=12).contains(&month)
+                || !(1..=31).contains(&day)
+            {
+                return None;
+            }
+            days_from_civil(year, month, day)
+        }
+        None => 0,
+    };
+    let mut parts = time.split(':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next().unwrap_or("0").parse().ok()?;
+    if parts.next().is_some()
+        || !(0..24).contains(&hours)
+        || !(0..60).contains(&minutes)
+        || !(0.0..61.0).contains(&seconds)
+    {
+        return None;
+    }
+    Some((days * 86_400 + hours * 3600 + minutes * 60) as f64 + seconds)
+}
+
+/// A (year, month, day) date to days since the unix epoch, see
+/// https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Days since the unix epoch to a (year, month, day) date, see
+/// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// millis() wraps after ~49 days and micros() after ~71 minutes, this keeps counting past the
+/// wrap. A large jump backwards is taken as a wrap, a small one as the Arduino resetting.
+#[derive(Debug, Default)]
+struct WrappingCounter {
+    last: Option<u32>,
+    epoch: u64,
+}
+
+impl WrappingCounter {
+    fn extend(&mut self, raw: u32) -> u64 {
+        match self.last {
+            Some(last) if raw < last && last - raw > u32::MAX / 2 => self.epoch += 1 << 32,
+            Some(last) if raw < last => self.epoch = 0,
+            _ => (),
+        }
+        self.last = Some(raw);
+        self.epoch + raw as u64
+    }
+}
+
+/// Turns the raw 32 bit device times from frames into the time since the Arduino started
+#[derive(Debug, Default)]
+pub struct DeviceClock {
+    millis: WrappingCounter,
+    micros: WrappingCounter,
+}
+
+impl DeviceClock {
+    pub fn extend(&mut self, time: DeviceTime) -> Duration {
+        match time {
+            DeviceTime::Millis(ms) => Duration::from_millis(self.millis.extend(ms)),
+            DeviceTime::Micros(us) => Duration::from_micros(self.micros.extend(us)),
+        }
+    }
+}
+
+/// Number of recent samples used to estimate the drift between the two clocks
+const SYNC_WINDOW: usize = 256;
+
+/// Estimates how the Arduino's clock maps onto the PC's clock.
+///
+/// The rate between the clocks is found with a least squares fit over recent samples, which
+/// handles the Arduino's crystal running slightly fast or slow. The offset is then taken from
+/// the sample that arrived quickest, as every sample is delayed by the relay but never early.
+#[derive(Debug)]
+pub struct ClockSync {
+    anchor: Instant,
+    samples: VecDeque<(f64, f64)>, // Device seconds & host seconds since the anchor
+    fit: Option<(f64, f64)>,       // Offset & rate
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            anchor: Instant::now(),
+            samples: VecDeque::with_capacity(SYNC_WINDOW),
+            fit: None,
+        }
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sample to the estimate, samples without a device time are ignored
+    pub fn add(&mut self, time: &SampleTime) {
+        let Some(device) = time.device else {
+            return;
+        };
+        let device = device.as_secs_f64();
+        let host = match time.host.checked_duration_since(self.anchor) {
+            Some(host) => host.as_secs_f64(),
+            None => -self.anchor.duration_since(time.host).as_secs_f64(),
+        };
+        // The device clock going backwards means it has reset, the old samples are useless
+        if matches!(self.samples.back(), Some((last, _)) if device < *last) {
+            self.samples.clear();
+        }
+        if self.samples.len() == SYNC_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((device, host));
+        self.fit = self.estimate();
+    }
+
+    fn estimate(&self) -> Option<(f64, f64)> {
+        let (first_device, _) = *self.samples.front()?;
+        let count = self.samples.len() as f64;
+        let rate = if self.samples.len() < 2 {
+            1.0
+        } else {
+            // Relative to the first sample to keep the sums small
+            let mean_device = self
+                .samples
+                .iter()
+                .map(|(d, _)| d - first_device)
+                .sum::<f64>()
+                / count;
+            let mean_host = self.samples.iter().map(|(_, h)| h).sum::<f64>() / count;
+            let mut covariance = 0.0;
+            let mut variance = 0.0;
+            for (d, h) in &self.samples {
+                let d = d - first_device - mean_device;
+                covariance += d * (h - mean_host);
+                variance += d * d;
+            }
+            match variance > 0.0 {
+                true => covariance / variance,
+                false => 1.0,
+            }
+        };
+        let offset = self
+            .samples
+            .iter()
+            .map(|(d, h)| h - rate * d)
+            .fold(f64::INFINITY, f64::min);
+        Some((offset, rate))
+    }
+
+    /// Maps a device time onto the PC's clock, None until a sample with a device time is added
+    pub fn to_host(&self, device: Duration) -> Option<Instant> {
+        let (offset, rate) = self.fit?;
+        let host = offset + rate * device.as_secs_f64();
+        match host >= 0.0 {
+            true => self.anchor.checked_add(Duration::from_secs_f64(host)),
+            false => self.anchor.checked_sub(Duration::from_secs_f64(-host)),
+        }
+    }
+
+    /// How far the Arduino's clock drifts from the PC's clock in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let (_, rate) = self.fit?;
+        Some((rate - 1.0) * 1_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extends_past_wrap() {
+        let mut counter = WrappingCounter::default();
+        assert_eq!(counter.extend(u32::MAX - 5), u32::MAX as u64 - 5);
+        assert_eq!(counter.extend(10), (1 << 32) + 10);
+        // A small step backwards is a reset
+        assert_eq!(counter.extend(2), 2);
+    }
+
+    #[test]
+    fn formats_utc() {
+        assert_eq!(format_utc(0.0), "1970-01-01 00:00:00.000");
+        assert_eq!(format_utc(951_827_696.25), "2000-02-29 12:34:56.250");
+        assert_eq!(format_utc(1_790_000_000.0), "2026-09-21 14:13:20.000");
+        assert_eq!(format_utc(-1.0), "1969-12-31 23:59:59.000");
+    }
+
+    #[test]
+    fn parses_utc() {
+        assert_eq!(parse_utc("1970-01-01 00:00:00"), Some(0.0));
+        assert_eq!(parse_utc("2000-02-29T12:34:56.250Z"), Some(951_827_696.25));
+        assert_eq!(parse_utc("1969-12-31 23:59:59"), Some(-1.0));
+        assert_eq!(
+            parse_utc("2026-09-21"),
+            Some(1_790_000_000.0 - 14.0 * 3600.0 - 800.0)
+        );
+        assert_eq!(parse_utc("01:00:30.5"), Some(3630.5));
+        assert_eq!(parse_utc("2026-13-01 00:00:00"), None);
+        assert_eq!(parse_utc("12.5"), None);
+        assert_eq!(parse_utc("25:00:00"), None);
+        assert_eq!(parse_utc("-3:00"), None);
+        assert_eq!(parse_utc("12:-5:00"), None);
+        assert_eq!(parse_utc("99999999999-01-01"), None);
+    }
+
+    #[test]
+    fn estimates_drift_and_offset() {
+        let mut sync = ClockSync::new();
+        let start = sync.anchor + Duration::from_secs(5);
+        // Device clock runs 100ppm slow, the device started 2s before start
+        for i in 0..SYNC_WINDOW as u64 {
+            let host_secs = i as f64;
+            let latency = if i % 7 == 0 {
+                0.0
+            } else {
+                0.003 * (i % 5) as f64
+            };
+            sync.add(&SampleTime {
+                host: start + Duration::from_secs_f64(host_secs + latency),
+                wall: SystemTime::now(),
+                device: Some(Duration::from_secs_f64(2.0 + host_secs * (1.0 - 100e-6))),
+            });
+        }
+        let drift = sync.drift_ppm().unwrap();
+        assert!((drift - 100.0).abs() < 20.0, "drift was {}", drift);
+        let mapped = sync.to_host(Duration::from_secs(2)).unwrap();
+        let error = match mapped > start {
+            true => mapped - start,
+            false => start - mapped,
+        };
+        assert!(error < Duration::from_millis(2), "error was {:?}", error);
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ *  Connections
+ *
+ *  Every open port gets its own Arduino, running as its own task with its own control channel.
+ *  The task owns the port, it sleeps until bytes arrive or it is told to stop, and closes the
+ *  port when it ends.
+ *
+ *  Messages going to the GUI are tagged with the device they came from, the device is named
+ *  after the port it was first opened on and keeps that name if the board is renamed by a
+ *  reconnect, so its data stays in one place. Everything the readers send goes through the
+ *  manager, which wakes the GUI as it is passed on so the GUI only repaints when there's news.
+ *  The manager also writes them to the capture file while recording, and runs the replay of a
+ *  capture, which sends through the same path as the readers. Messages that aren't from a
+ *  device, such as a recording failing, have an empty device name.
+ */
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::arduino::{Arduino, ThreadMSG};
+use crate::capture::{CaptureError, CaptureEvent, CaptureWriter, FLUSH_INTERVAL};
+use crate::clock::SampleTime;
+use crate::replay::{self, ReplayCommand};
+use crate::serial_settings::SerialSettings;
+
+/// Device name & the message it sent
+pub type DeviceMSG = (String, ThreadMSG);
+
+/// A device's reader task
+struct Reader {
+    control: mpsc::Sender<ThreadMSG>,
+    task: JoinHandle<()>,
+}
+
+/// How many messages the readers can get ahead of the manager
+const READER_CAPACITY: usize = 1024;
+
+pub struct ConnectionManager {
+    connections: HashMap<String, Reader>,
+    events: mpsc::Sender<DeviceMSG>,
+    on_event: Box<dyn Fn() + Send>, // Wakes the GUI
+    recording: Option<CaptureWriter>,
+    replay: Option<mpsc::Sender<ReplayCommand>>, // Controls the capture being replayed
+}
+
+impl ConnectionManager {
+    /// `on_event` is called after each message is passed on to the GUI
+    pub fn new(events: mpsc::Sender<DeviceMSG>, on_event: impl Fn() + Send + 'static) -> Self {
+        Self {
+            connections: HashMap::new(),
+            events,
+            on_event: Box::new(on_event),
+            recording: None,
+            replay: None,
+        }
+    }
+
+    /// Handles Start & Disconnect messages from the GUI and passes on what the readers send,
+    /// until the GUI goes away
+    pub async fn run(mut self, mut commands: mpsc::Receiver<ThreadMSG>) {
+        let (readers, mut from_readers) = mpsc::channel(READER_CAPACITY);
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = commands.recv() => match msg {
+                    Some(ThreadMSG::Start((port, settings))) => self.open(port, settings, &readers),
+                    Some(ThreadMSG::Disconnect(device)) => self.close(&device),
+                    Some(ThreadMSG::StartRecording(path)) => self.start_recording(&path).await,
+                    Some(ThreadMSG::StopRecording()) => self.stop_recording().await,
+                    Some(ThreadMSG::StartReplay((path, id))) => {
+                        self.start_replay(path, id, &readers)
+                    }
+                    Some(ThreadMSG::ReplayControl(command)) => self.control_replay(command),
+                    Some(_) => {} // Only sent to the GUI
+                    None => break,
+                },
+                Some(event) = from_readers.recv() => {
+                    self.record(&event).await;
+                    if self.events.send(event).await.is_err() {
+                        break;
+                    }
+                    (self.on_event)();
+                }
+                _ = flush.tick(), if self.recording.is_some() => {
+                    if let Some(Err(e)) = self.recording.as_mut().map(CaptureWriter::flush) {
+                        self.recording_failed(e).await;
+                    }
+                }
+            }
+        }
+        self.stop_recording().await;
+        // The GUI has gone, let every reader close its port before returning. Nothing is read
+        // from them any more so they can't get stuck sending.
+        drop(from_readers);
+        for (device, reader) in self.connections.drain() {
+            let _ = reader.control.try_send(ThreadMSG::Disconnect(device));
+            let _ = reader.task.await;
+        }
+    }
+
+    /// Opens the port as a new device, unless it is already open
+    fn open(&mut self, port: String, settings: SerialSettings, events: &mpsc::Sender<DeviceMSG>) {
+        if let Some(reader) = self.connections.get(&port) {
+            if !reader.task.is_finished() {
+                eprintln!("{} {}", "Already connected to".red(), port);
+                return;
+            }
+        }
+        let (control, mut control_rx) = mpsc::channel(8);
+        let events = events.clone();
+        let device = port.clone();
+        self.connections.remove(&device);
+        let key = device.clone();
+        let task = tokio::spawn(async move {
+            let mut arduino = Arduino::new(device.clone());
+            match arduino.connect(port.clone(), &settings).await {
+                Ok(()) => arduino.read_loop(&mut control_rx, events).await,
+                Err(e) => {
+                    let _ = events
+                        .send((device, ThreadMSG::ConnectFailed((port, e))))
+                        .await;
+                }
+            }
+        });
+        self.connections.insert(key, Reader { control, task });
+    }
+
+    /// Plays the capture back, replacing any replay already playing
+    fn start_replay(&mut self, path: PathBuf, id: u64, events: &mpsc::Sender<DeviceMSG>) {
+        let (control, control_rx) = mpsc::channel(8);
+        // Dropping the old sender stops the old replay
+        self.replay = Some(control);
+        tokio::spawn(replay::replay(path, id, control_rx, events.clone()));
+    }
+
+    fn control_replay(&mut self, command: ReplayCommand) {
+        let Some(control) = &self.replay else {
+            return;
+        };
+        if command == ReplayCommand::Stop {
+            self.replay = None;
+        } else if control.try_send(command).is_err() {
+            eprintln!("{}", "Replay is not responding".red());
+        }
+    }
+
+    /// Starts writing everything received to a new capture file, replacing any recording
+    async fn start_recording(&mut self, path: &Path) {
+        self.stop_recording().await;
+        match CaptureWriter::create(path) {
+            Ok(writer) => self.recording = Some(writer),
+            Err(e) => self.recording_failed(e).await,
+        }
+    }
+
+    async fn stop_recording(&mut self) {
+        if let Some(Err(e)) = self.recording.as_mut().map(CaptureWriter::flush) {
+            self.recording_failed(e).await;
+        }
+        self.recording = None;
+    }
+
+    /// Stops recording and tells the GUI why
+    async fn recording_failed(&mut self, e: CaptureError) {
+        let path = match self.recording.take() {
+            Some(writer) => writer.path().display().to_string(),
+            None => "capture".to_owned(),
+        };
+        eprintln!("{} {}: {}", "Recording failed".red(), path, e);
+        let msg = ThreadMSG::RecordingFailed(format!("{}: {}", path, e));
+        if self.events.send((String::new(), msg)).await.is_ok() {
+            (self.on_event)();
+        }
+    }
+
+    /// Writes the message to the capture file if it's something worth keeping
+    async fn record(&mut self, (device, msg): &DeviceMSG) {
+        let Some(writer) = self.recording.as_mut() else {
+            return;
+        };
+        let (time, event) = match msg {
+            ThreadMSG::Data(data) => (
+                data.time().copied().unwrap_or_else(SampleTime::now),
+                CaptureEvent::Data(data.clone()),
+            ),
+            ThreadMSG::Raw((bytes, time)) => (*time, CaptureEvent::Raw(bytes.clone())),
+            ThreadMSG::ConnectionLost(_) => (SampleTime::now(), CaptureEvent::Lost),
+            _ => return,
+        };
+        if let Err(e) = writer.write(device, &time, &event) {
+            self.recording_failed(e).await;
+        }
+    }
+
+    fn close(&mut self, device: &str) {
+        match self.connections.remove(device) {
+            Some(reader) => {
+                // Dropping the sender also stops the reader, the message just makes it sooner
+                let _ = reader
+                    .control
+                    .try_send(ThreadMSG::Disconnect(device.to_owned()));
+            }
+            None => eprintln!("Cannot disconnect: {} is not connected!", device),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduino::ConnectError;
+
+    /// Stands in for a reader task, passes on what it's told & stops like a reader does
+    fn fake_reader(device: &str, told: mpsc::Sender<DeviceMSG>) -> Reader {
+        let (control, mut control_rx) = mpsc::channel(8);
+        let device = device.to_owned();
+        let task = tokio::spawn(async move {
+            while let Some(msg) = control_rx.recv().await {
+                let stop = matches!(msg, ThreadMSG::Disconnect(_));
+                let _ = told.send((device.clone(), msg)).await;
+                if stop {
+                    break;
+                }
+            }
+        });
+        Reader { control, task }
+    }
+
+    fn is_disconnect(told: Option<DeviceMSG>, device: &str) -> bool {
+        matches!(told, Some((d, ThreadMSG::Disconnect(to))) if d == device && to == device)
+    }
+
+    #[tokio::test]
+    async fn reports_failed_connection_for_device() {
+        let (events, mut gui) = mpsc::channel(8);
+        let (commands, commands_rx) = mpsc::channel(8);
+        let manager = tokio::spawn(ConnectionManager::new(events, || {}).run(commands_rx));
+        let port = "/dev/this-port-does-not-exist".to_owned();
+        commands
+            .send(ThreadMSG::Start((port.clone(), SerialSettings::default())))
+            .await
+            .unwrap();
+        let (device, msg) = gui.recv().await.unwrap();
+        assert_eq!(device, port);
+        assert!(matches!(
+            msg,
+            ThreadMSG::ConnectFailed((p, ConnectError::NotFound(_) | ConnectError::Other(_)))
+                if p == port
+        ));
+        // The manager stops once the GUI's sender is gone
+        drop(commands);
+        manager.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_start_for_an_open_port() {
+        let (events, mut gui) = mpsc::channel(8);
+        let (told, mut reader) = mpsc::channel(8);
+        let mut manager = ConnectionManager::new(events, || {});
+        manager
+            .connections
+            .insert("COM3".to_owned(), fake_reader("COM3", told));
+        let (commands, commands_rx) = mpsc::channel(8);
+        let manager = tokio::spawn(manager.run(commands_rx));
+        commands
+            .send(ThreadMSG::Start((
+                "COM3".to_owned(),
+                SerialSettings::default(),
+            )))
+            .await
+            .unwrap();
+        commands
+            .send(ThreadMSG::Disconnect("COM3".to_owned()))
+            .await
+            .unwrap();
+        // The reader that was already running is the one told to stop
+        assert!(is_disconnect(reader.recv().await, "COM3"));
+        drop(commands);
+        manager.await.unwrap();
+        // Nothing tried to open the port a second time
+        assert!(gui.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disconnects_only_the_named_device() {
+        let (events, _gui) = mpsc::channel(8);
+        let (told, mut readers) = mpsc::channel(8);
+        let mut manager = ConnectionManager::new(events, || {});
+        for device in ["COM3", "COM4"] {
+            manager
+                .connections
+                .insert(device.to_owned(), fake_reader(device, told.clone()));
+        }
+        drop(told);
+        let (commands, commands_rx) = mpsc::channel(8);
+        let manager = tokio::spawn(manager.run(commands_rx));
+        for device in ["COM9", "COM4"] {
+            commands
+                .send(ThreadMSG::Disconnect(device.to_owned()))
+                .await
+                .unwrap();
+        }
+        assert!(is_disconnect(readers.recv().await, "COM4"));
+        commands
+            .send(ThreadMSG::Disconnect("COM3".to_owned()))
+            .await
+            .unwrap();
+        assert!(is_disconnect(readers.recv().await, "COM3"));
+        drop(commands);
+        manager.await.unwrap();
+        // Both were closed already, so neither is told again as the manager stops
+        assert!(readers.recv().await.is_none());
+    }
+}
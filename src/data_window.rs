@@ -6,33 +6,67 @@
  *      Direction of rotation
  */
 
-use std::{fmt::Display, ops::Add, slice::Iter, time::Instant};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    slice::Iter,
+    time::Instant,
+};
 
 use egui::ScrollArea;
-use egui_plot::{Line, Plot, PlotPoints};
+use egui_plot::{Legend, Line, LineStyle, Plot, PlotPoints, VLine};
 
 use crate::arduino::PacketData;
+use crate::clock::{format_utc, unix_secs, ClockSync, SampleTime};
+use crate::device::{ChannelDescriptor, ChannelMetadata, Scaling};
+use crate::layout::{FieldValue, StructLayout};
+use crate::store::{format_bytes, Bucket, ChannelKey, ChannelStore};
 
 #[derive(Clone, Debug)]
 pub struct DataWindow {
     window_name: String,
+    pub device: String, // The connection the data comes from
     pub selected_data: usize,
     data_cap: usize,
     display_type: DisplayType,
+    struct_layout: String, // Only used by binary data
+    xy_fields: [usize; 2], // Only used by vector data
+    clock: Clock,
+    absolute_time: bool,       // Show UTC times instead of seconds ago
+    range: Option<(f64, f64)>, // Expected range from the device, always kept in view
+    scaling: Scaling,
+    show_history: bool,        // Draw the downsampled history behind the samples
+    overlays: Vec<ChannelKey>, // Other channels drawn on the same graph, such as imported data
+    overlay_shift: f64,        // Seconds the overlays are moved later by
+}
+
+/// Which clock is used for the time axis
+#[derive(Clone, Debug, PartialEq)]
+pub enum Clock {
+    Host,   // When the PC received the sample
+    Device, // The Arduino's clock, mapped onto the PC's clock
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DisplayType {
     Graph,
+    XY,
     Text,
+    HexDump,
+    BitField,
+    Struct,
     NoDisplay,
 }
 
 impl DisplayType {
     pub fn iterator() -> Iter<'static, DisplayType> {
-        static DISPLAYS: [DisplayType; 3] = [
+        static DISPLAYS: [DisplayType; 7] = [
             DisplayType::Graph,
+            DisplayType::XY,
             DisplayType::Text,
+            DisplayType::HexDump,
+            DisplayType::BitField,
+            DisplayType::Struct,
             DisplayType::NoDisplay,
         ];
         DISPLAYS.iter()
@@ -43,48 +77,107 @@ impl Default for DataWindow {
     fn default() -> Self {
         Self {
             window_name: "Name Not Set!".to_owned(),
+            device: String::new(),
             selected_data: 1337420,
             display_type: DisplayType::NoDisplay,
             data_cap: 100,
+            struct_layout: String::new(),
+            xy_fields: [0, 1],
+            clock: Clock::Host,
+            absolute_time: false,
+            range: None,
+            scaling: Scaling::default(),
+            show_history: true,
+            overlays: Vec::new(),
+            overlay_shift: 0.0,
         }
     }
 }
 
 impl DataWindow {
-    pub fn new(window_name: String, selected_data: usize) -> Self {
+    pub fn new(window_name: String, device: String, selected_data: usize) -> Self {
         Self {
             window_name,
+            device,
             selected_data,
             display_type: DisplayType::NoDisplay,
             data_cap: 100,
+            struct_layout: String::new(),
+            xy_fields: [0, 1],
+            clock: Clock::Host,
+            absolute_time: false,
+            range: None,
+            scaling: Scaling::default(),
+            show_history: true,
+            overlays: Vec::new(),
+            overlay_shift: 0.0,
         }
     }
 
-    pub fn show(&mut self, ctx: &egui::Context, data: &Vec<PacketData>, open: &mut bool) {
-        let Self {
-            window_name,
-            selected_data,
-            display_type,
-            data_cap,
-        } = self.clone();
+    /// A window for a channel the device announced
+    pub fn for_channel(device: String, channel: &ChannelDescriptor) -> Self {
+        let mut window = Self::new(channel.name.clone(), device, channel.id as usize);
+        window.display_type = channel.display_type();
+        window.set_channel(channel);
+        window
+    }
+
+    /// Updates the window to match a channel that was announced again
+    pub fn set_channel(&mut self, channel: &ChannelDescriptor) {
+        self.window_name = channel.name.clone();
+        self.range = channel.range;
+        self.scaling.unit = channel.unit.clone();
+    }
 
-        let mut window = egui::Window::new(window_name)
-            .id(egui::Id::new(format!("{}", &self.selected_data)))
+    /// Names the window and sets how its values are scaled, an empty name keeps the current one
+    pub fn set_metadata(&mut self, metadata: &ChannelMetadata) {
+        if !metadata.name.is_empty() {
+            self.window_name = metadata.name.clone();
+        }
+        self.scaling = metadata.scaling.clone();
+    }
+
+    /// Identifies the window's data across every device
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.device, self.selected_data)
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        store: &ChannelStore,
+        open: &mut bool,
+        clock_sync: &ClockSync,
+        channels: &BTreeMap<ChannelKey, ChannelStore>, // Everything that can be overlaid
+    ) {
+        let window = egui::Window::new(self.window_name.clone())
+            .id(egui::Id::new(self.key()))
             .resizable(true)
             .open(open)
             .constrain(true)
             .title_bar(true)
             .collapsible(true);
-        window.show(ctx, |ui| self.ui(ui, data));
+        window.show(ctx, |ui| self.ui(ui, store, clock_sync, channels));
     }
 
-    fn ui(&mut self, ui: &mut egui::Ui, data: &Vec<PacketData>) {
+    fn ui(
+        &mut self,
+        ui: &mut egui::Ui,
+        store: &ChannelStore,
+        clock_sync: &ClockSync,
+        channels: &BTreeMap<ChannelKey, ChannelStore>,
+    ) {
+        let data = store.samples();
         ui.vertical(|ui| {
             ui.horizontal(|ui| {
                 ui.label("Data Name:");
                 ui.text_edit_singleline(&mut self.window_name);
             });
-            // Allow limiting of shown data, maybe it prevents running out of memory?
+            ui.label(format!(
+                "Device: {} | ID: {}",
+                self.device, self.selected_data
+            ));
+            // Only limits what is shown, how much is stored is set in the Storage menu
             ui.horizontal(|ui| {
                 ui.label("Limit output:");
                 ui.add(
@@ -93,10 +186,16 @@ impl DataWindow {
                         .clamp_range(0.0..=f32::MAX),
                 );
             });
-            match data.get(0) {
-                Some(dat) => ui.label(format!("Data Type: {}", dat.display_variant())),
-                None => ui.label(format!("UNKNOWN TYPE!")),
+            match store.kind() {
+                Some(kind) => ui.label(format!("Data Type: {}", kind)),
+                None => ui.label("UNKNOWN TYPE!"),
             };
+            ui.label(format!(
+                "Stored: {} samples & {} history buckets ({})",
+                store.len(),
+                store.history().len(),
+                format_bytes(store.memory_usage())
+            ));
             ui.horizontal(|ui| {
                 egui::ComboBox::from_label("Display Type")
                     .selected_text(format!("{:?}", self.display_type))
@@ -111,63 +210,572 @@ impl DataWindow {
                     });
                 ui.end_row();
             });
+            ui.checkbox(&mut self.absolute_time, "Absolute times (UTC)");
+            if !self.scaling.is_identity() {
+                ui.label(format!(
+                    "Scaled: raw * {} + {}",
+                    self.scaling.scale, self.scaling.offset
+                ));
+            }
 
             ui.separator();
+            // The latest sample decides, the channel may have changed type part way through
+            let Some(latest) = latest_sample(data) else {
+                ui.label("No data received yet!");
+                return;
+            };
             let data_2 = data.iter().rev().collect::<Vec<&PacketData>>();
+            let no_history = VecDeque::new();
             match self.display_type {
-                DisplayType::Graph => match data[0] {
-                    PacketData::Integer(_, _, _) | PacketData::Float(_, _, _) => {
-                        plot_data(ui, &self.window_name, &mut self.data_cap.clone(), &data_2)
+                DisplayType::Graph => match latest {
+                    PacketData::Integer(_, _, _)
+                    | PacketData::Float(_, _, _)
+                    | PacketData::Vector(_, _, _) => {
+                        let clock_sync = self.clock_ui(ui, clock_sync);
+                        ui.checkbox(&mut self.show_history, "Show history");
+                        let history = match self.show_history {
+                            true => store.history(),
+                            false => &no_history,
+                        };
+                        self.overlay_ui(ui, channels);
+                        let overlays: Vec<(String, &VecDeque<PacketData>)> = self
+                            .overlays
+                            .iter()
+                            .filter_map(|key| {
+                                Some((overlay_name(key), channels.get(key)?.samples()))
+                            })
+                            .collect();
+                        plot_data(
+                            ui,
+                            &self.key(),
+                            &mut self.data_cap.clone(),
+                            &data_2,
+                            history,
+                            clock_sync,
+                            self.absolute_time,
+                            self.range,
+                            &self.scaling,
+                            &overlays,
+                            self.overlay_shift,
+                        )
                     }
                     _ => {
                         ui.label("Graph not supported for the following data type!");
                     }
                 },
+                DisplayType::XY => match latest {
+                    PacketData::Vector(values, _, _) => {
+                        let field_count = values.len();
+                        ui.horizontal(|ui| {
+                            for (label, field) in ["X:", "Y:"].iter().zip(&mut self.xy_fields) {
+                                ui.label(*label);
+                                ui.add(
+                                    egui::DragValue::new(field)
+                                        .prefix("Field ")
+                                        .clamp_range(0..=field_count.saturating_sub(1)),
+                                );
+                            }
+                        });
+                        plot_xy(
+                            ui,
+                            &self.key(),
+                            &mut self.data_cap.clone(),
+                            &data_2,
+                            self.xy_fields,
+                            &self.scaling,
+                        )
+                    }
+                    _ => {
+                        ui.label("Only vector data can be plotted against itself!");
+                    }
+                },
                 DisplayType::Text => {
                     ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
-                        let tmp_string: String = get_text(data);
+                        let tmp_string: String = get_text(data, self.absolute_time, &self.scaling);
                         ui.label(&tmp_string);
                     });
                 }
+                DisplayType::HexDump | DisplayType::BitField | DisplayType::Struct => {
+                    match latest {
+                        PacketData::Binary(_, _, _) => self.binary_ui(ui, data),
+                        _ => {
+                            ui.label("Only binary data can be displayed this way!");
+                        }
+                    }
+                }
                 _ => (),
             }
         });
     }
 }
 
-fn plot_data(ui: &mut egui::Ui, window_name: &String, cap: &mut usize, data: &Vec<&PacketData>) {
-    let mut plot = Plot::new(format!("{}", window_name));
+impl DataWindow {
+    /// Picks other channels to draw on the graph & lines them up with this one
+    fn overlay_ui(&mut self, ui: &mut egui::Ui, channels: &BTreeMap<ChannelKey, ChannelStore>) {
+        let own = (self.device.clone(), self.selected_data as u8);
+        let candidates: Vec<&ChannelKey> = channels
+            .iter()
+            .filter(|(key, store)| {
+                **key != own
+                    && !self.overlays.contains(key)
+                    && matches!(store.kind(), Some("Integer" | "Float" | "Vector"))
+            })
+            .map(|(key, _)| key)
+            .collect();
+        ui.horizontal(|ui| {
+            ui.label("Overlay:");
+            egui::ComboBox::from_id_source((self.key(), "overlay"))
+                .selected_text("Add...")
+                .show_ui(ui, |ui| {
+                    for key in candidates {
+                        if ui.selectable_label(false, overlay_name(key)).clicked() {
+                            self.overlays.push(key.clone());
+                        }
+                    }
+                });
+            self.overlays.retain(|key| {
+                let removed = ui.button(format!("✖ {}", overlay_name(key))).clicked();
+                !removed
+            });
+        });
+        if self.overlays.is_empty() {
+            return;
+        }
+        ui.horizontal(|ui| {
+            ui.label("Shift overlays by");
+            ui.add(
+                egui::DragValue::new(&mut self.overlay_shift)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+            // Lines the start of the overlays up with now, for comparing against a run that's
+            // just starting
+            let first = self
+                .overlays
+                .iter()
+                .filter_map(|key| channels.get(key)?.samples().front()?.time())
+                .map(|time| time.host)
+                .min();
+            if let Some(first) = first {
+                if ui.button("Start now").clicked() {
+                    self.overlay_shift = Instant::now()
+                        .saturating_duration_since(first)
+                        .as_secs_f64();
+                }
+            }
+        });
+    }
+
+    /// Lets the user pick the clock for the time axis, returns the clock sync to use if the
+    /// device clock was picked
+    fn clock_ui<'a>(
+        &mut self,
+        ui: &mut egui::Ui,
+        clock_sync: &'a ClockSync,
+    ) -> Option<&'a ClockSync> {
+        ui.horizontal(|ui| {
+            ui.label("Time axis:");
+            ui.radio_value(&mut self.clock, Clock::Host, "PC");
+            ui.radio_value(&mut self.clock, Clock::Device, "Arduino");
+            if self.clock == Clock::Device {
+                match clock_sync.drift_ppm() {
+                    Some(drift) => ui.label(format!("Drift: {:.1} ppm", drift)),
+                    None => ui.label("No Arduino timestamps received!"),
+                };
+            }
+        });
+        match self.clock {
+            Clock::Host => None,
+            Clock::Device => Some(clock_sync),
+        }
+    }
+
+    fn binary_ui(&mut self, ui: &mut egui::Ui, data: &VecDeque<PacketData>) {
+        let layout = match self.display_type {
+            DisplayType::Struct => {
+                ui.label("Struct layout (e.g. 'u8 direction, i16 revolutions, f32 newtons'):");
+                ui.text_edit_multiline(&mut self.struct_layout);
+                match StructLayout::parse(&self.struct_layout) {
+                    Ok(layout) => {
+                        ui.label(format!("Struct size: {} bytes", layout.size()));
+                        Some(layout)
+                    }
+                    Err(e) => {
+                        ui.label(e);
+                        return;
+                    }
+                }
+            }
+            _ => None,
+        };
+        ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
+            let mut tmp = String::new();
+            for d in data {
+                if let PacketData::Binary(bytes, _, t) = d {
+                    let text = match (&self.display_type, &layout) {
+                        (DisplayType::BitField, _) => format_bits(bytes),
+                        (DisplayType::Struct, Some(layout)) => format_struct(layout, bytes),
+                        _ => format_hex(bytes),
+                    };
+                    tmp += &format_text(text, t, self.absolute_time);
+                }
+            }
+            ui.monospace(tmp);
+        });
+    }
+}
+
+/// Where the sample goes on the time axis, either seconds since it was taken or seconds since
+/// the unix epoch if absolute. The Arduino's clock is used if a clock sync is given, samples
+/// without an Arduino timestamp can't be placed on the Arduino's clock
+/// Newest sample that isn't a gap, a lost connection shouldn't change how the channel is shown
+fn latest_sample(data: &VecDeque<PacketData>) -> Option<&PacketData> {
+    data.iter()
+        .rev()
+        .find(|d| !matches!(d, PacketData::Gap(_, _)))
+}
+
+fn time_axis(time: &SampleTime, clock_sync: Option<&ClockSync>, absolute: bool) -> Option<f64> {
+    let host = match clock_sync {
+        None => time.host,
+        Some(clock_sync) => clock_sync.to_host(time.device?)?,
+    };
+    match absolute {
+        true => Some(unix_secs(time.wall_at(host))),
+        false => Some(Instant::now().saturating_duration_since(host).as_secs_f64()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn plot_data(
+    ui: &mut egui::Ui,
+    window_name: &String,
+    cap: &mut usize,
+    data: &Vec<&PacketData>,
+    history: &VecDeque<Bucket>,
+    clock_sync: Option<&ClockSync>,
+    absolute: bool,
+    range: Option<(f64, f64)>,
+    scaling: &Scaling,
+    overlays: &[(String, &VecDeque<PacketData>)],
+    overlay_shift: f64,
+) {
+    let mut plot = Plot::new(window_name)
+        .legend(Legend::default())
+        .y_axis_label(scaling.unit.clone());
+    if let Some((min, max)) = range {
+        plot = plot.include_y(min).include_y(max);
+    }
+    if absolute {
+        // Only the time of day fits under each grid line, the full date is shown on hover
+        let unit = scaling.unit.clone();
+        plot = plot
+            .x_axis_formatter(|mark, _, _| format_utc(mark.value)[11..19].to_owned())
+            .label_formatter(move |name, value| {
+                format!("{}\n{}\n{:.3} {}", name, format_utc(value.x), value.y, unit)
+            });
+    }
+    if cap > &mut data.len() {
+        *cap = data.len()
+    }
+    plot.show(ui, |plot_ui| {
+        // Vectors get a line for each of their fields, lines are broken where the connection
+        // was lost
+        let mut lines: Vec<Vec<Vec<[f64; 2]>>> = Vec::new();
+        let mut gaps = Vec::new();
+        for d in &data[..*cap] {
+            if let PacketData::Gap(_, t1) = d {
+                gaps.extend(time_axis(t1, clock_sync, absolute));
+                for segments in &mut lines {
+                    segments.push(Vec::new());
+                }
+                continue;
+            }
+            let (Some(values), Some(t1)) = (d.values(), d.time()) else {
+                continue;
+            };
+            let Some(x) = time_axis(t1, clock_sync, absolute) else {
+                continue;
+            };
+            if lines.len() < values.len() {
+                lines.resize(values.len(), vec![Vec::new()]);
+            }
+            for (segments, value) in lines.iter_mut().zip(values) {
+                segments.last_mut().unwrap().push([x, scaling.apply(value)]);
+            }
+        }
+        let is_vector = lines.len() > 1;
+        for (i, segments) in lines.into_iter().enumerate() {
+            // Every segment of a field has the same colour & name so they read as one line
+            let color = field_color(i);
+            for points in segments.into_iter().filter(|s| !s.is_empty()) {
+                let mut line = Line::new(PlotPoints::from(points)).color(color);
+                if is_vector {
+                    line = line.name(format!("Field {}", i));
+                }
+                plot_ui.line(line);
+            }
+        }
+        plot_history(plot_ui, history, clock_sync, absolute, scaling, is_vector);
+        for (i, (name, samples)) in overlays.iter().enumerate() {
+            plot_overlay(plot_ui, name, samples, absolute, overlay_shift, i);
+        }
+        for x in gaps {
+            plot_ui.vline(
+                VLine::new(x)
+                    .color(egui::Color32::GRAY)
+                    .style(LineStyle::dashed_loose())
+                    .name("Connection lost"),
+            );
+        }
+    });
+}
+
+/// Draws the mean of each field with a faint band from its min to its max
+fn plot_history(
+    plot_ui: &mut egui_plot::PlotUi,
+    history: &VecDeque<Bucket>,
+    clock_sync: Option<&ClockSync>,
+    absolute: bool,
+    scaling: &Scaling,
+    is_vector: bool,
+) {
+    // Mean, min & max for each field, broken up at gaps like the samples
+    let mut lines: Vec<[Vec<Vec<[f64; 2]>>; 3]> = Vec::new();
+    for bucket in history {
+        let Some(x) = time_axis(&bucket.start, clock_sync, absolute) else {
+            continue;
+        };
+        if lines.len() < bucket.min.len() {
+            lines.resize(
+                bucket.min.len(),
+                [vec![Vec::new()], vec![Vec::new()], vec![Vec::new()]],
+            );
+        }
+        let mean = bucket.mean();
+        for (i, field) in lines.iter_mut().enumerate().take(mean.len()) {
+            for (segments, value) in field
+                .iter_mut()
+                .zip([mean[i], bucket.min[i], bucket.max[i]])
+            {
+                if bucket.after_gap {
+                    segments.push(Vec::new());
+                }
+                segments.last_mut().unwrap().push([x, scaling.apply(value)]);
+            }
+        }
+    }
+    for (i, [mean, min, max]) in lines.into_iter().enumerate() {
+        let color = field_color(i);
+        let name = match is_vector {
+            true => format!("Field {} history", i),
+            false => "History".to_owned(),
+        };
+        for points in mean.into_iter().filter(|s| !s.is_empty()) {
+            plot_ui.line(Line::new(PlotPoints::from(points)).color(color).name(&name));
+        }
+        for points in min.into_iter().chain(max).filter(|s| !s.is_empty()) {
+            plot_ui.line(
+                Line::new(PlotPoints::from(points))
+                    .color(color.gamma_multiply(0.4))
+                    .name(&name),
+            );
+        }
+    }
+}
+
+/// Draws another channel's samples as dashed lines, on the PC's clock & unscaled since they
+/// belong to another channel
+fn plot_overlay(
+    plot_ui: &mut egui_plot::PlotUi,
+    name: &str,
+    samples: &VecDeque<PacketData>,
+    absolute: bool,
+    shift: f64,
+    index: usize,
+) {
+    let mut lines: Vec<Vec<Vec<[f64; 2]>>> = Vec::new();
+    for sample in samples {
+        if let PacketData::Gap(_, _) = sample {
+            for segments in &mut lines {
+                segments.push(Vec::new());
+            }
+            continue;
+        }
+        let (Some(values), Some(time)) = (sample.values(), sample.time()) else {
+            continue;
+        };
+        let Some(x) = time_axis(&time.shifted(shift), None, absolute) else {
+            continue;
+        };
+        if lines.len() < values.len() {
+            lines.resize(values.len(), vec![Vec::new()]);
+        }
+        for (segments, value) in lines.iter_mut().zip(values) {
+            segments.last_mut().unwrap().push([x, value]);
+        }
+    }
+    let is_vector = lines.len() > 1;
+    for (i, segments) in lines.into_iter().enumerate() {
+        // Picked from the far side of the hue circle to the channel's own fields
+        let color = field_color(index * 3 + i + 5);
+        let name = match is_vector {
+            true => format!("{} [{}]", name, i),
+            false => name.to_owned(),
+        };
+        for points in segments.into_iter().filter(|s| !s.is_empty()) {
+            plot_ui.line(
+                Line::new(PlotPoints::from(points))
+                    .color(color)
+                    .style(LineStyle::dashed_dense())
+                    .name(&name),
+            );
+        }
+    }
+}
+
+/// How an overlaid channel is named on the graph
+fn overlay_name((device, id): &ChannelKey) -> String {
+    format!("{} ID {}", device, id)
+}
+
+/// Spreads the colours out around the hue circle, the same way egui_plot picks them
+fn field_color(index: usize) -> egui::Color32 {
+    let golden_ratio = (5.0_f32.sqrt() - 1.0) / 2.0;
+    egui::ecolor::Hsva::new(index as f32 * golden_ratio, 0.85, 0.5, 1.0).into()
+}
+
+/// Plots one field of a vector against another
+fn plot_xy(
+    ui: &mut egui::Ui,
+    window_name: &String,
+    cap: &mut usize,
+    data: &Vec<&PacketData>,
+    [x, y]: [usize; 2],
+    scaling: &Scaling,
+) {
+    let plot = Plot::new(format!("{} XY", window_name))
+        .x_axis_label(scaling.unit.clone())
+        .y_axis_label(scaling.unit.clone());
     if cap > &mut data.len() {
         *cap = data.len()
     }
     plot.show(ui, |plot_ui| {
         let points: PlotPoints = data[..*cap]
             .iter()
-            .map(|d| match *d {
-                PacketData::Integer(d1, _, t1) => [t1.elapsed().as_secs_f64(), *d1 as f64],
-                PacketData::Float(d1, _, t1) => [t1.elapsed().as_secs_f64(), *d1 as f64],
-                _ => [0.0, 0.0],
+            .filter_map(|d| match *d {
+                PacketData::Vector(d1, _, _) => Some([
+                    scaling.apply(d1.get(x)?.as_f64()),
+                    scaling.apply(d1.get(y)?.as_f64()),
+                ]),
+                _ => None,
             })
             .collect();
-        let line = Line::new(points);
-        plot_ui.line(line);
+        plot_ui.line(Line::new(points));
     });
 }
 
-fn get_text(data: &Vec<PacketData>) -> String {
+fn get_text(data: &VecDeque<PacketData>, absolute: bool, scaling: &Scaling) -> String {
     let mut tmp = String::new();
     for d in data {
         tmp = tmp
             + &match d {
-                PacketData::Float(d, _, t) => format_text(d, t),
-                PacketData::String(d, _, t) => format_text(d, t),
-                PacketData::Integer(d, _, t) => format_text(d, t),
+                PacketData::Float(d, _, t) => {
+                    let value = scaling.format_value(d, *d);
+                    format_text(scaling.with_unit(value), t, absolute)
+                }
+                PacketData::String(d, _, t) => format_text(d, t, absolute),
+                PacketData::Integer(d, _, t) => {
+                    let value = scaling.format_value(d, *d as f64);
+                    format_text(scaling.with_unit(value), t, absolute)
+                }
+                PacketData::Binary(d, _, t) => format_text(format_hex(d), t, absolute),
+                PacketData::Gap(_, t) => format_text("--- Connection lost ---", t, absolute),
+                PacketData::Vector(d, _, t) => {
+                    format_text(scaling.with_unit(format_vector(d, scaling)), t, absolute)
+                }
                 _ => "Unknown Data Type\n".to_string(),
             };
     }
     tmp
 }
 
-fn format_text<D: Display>(data: D, time: &Instant) -> String {
-    format!("[{:>4.2}] {}\n", time.elapsed().as_secs_f32(), &data)
+fn format_text<D: Display>(data: D, time: &SampleTime, absolute: bool) -> String {
+    let received = match absolute {
+        true => format_utc(unix_secs(time.wall)),
+        false => format!("{:>4.2}", time.elapsed().as_secs_f32()),
+    };
+    match time.device {
+        Some(device) => format!(
+            "[{}] [Arduino {:.3}] {}\n",
+            received,
+            device.as_secs_f64(),
+            &data
+        ),
+        None => format!("[{}] {}\n", received, &data),
+    }
+}
+
+fn format_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn format_bits(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:08b}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn format_struct(layout: &StructLayout, bytes: &[u8]) -> String {
+    let mut fields = layout
+        .decode(bytes)
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>();
+    if bytes.len() != layout.size() {
+        fields.push(format!(
+            "({} bytes, expected {})",
+            bytes.len(),
+            layout.size()
+        ));
+    }
+    fields.join(" ")
+}
+
+/// Every field of a vector shares the channel's scaling
+fn format_vector(values: &[FieldValue], scaling: &Scaling) -> String {
+    let values = values
+        .iter()
+        .map(|v| scaling.format_value(v, v.as_f64()))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!("({})", values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_ignores_a_trailing_gap() {
+        let data = VecDeque::from([
+            PacketData::Float(1.5, 0, SampleTime::now()),
+            PacketData::Gap(0, SampleTime::now()),
+        ]);
+        // Still drawn as a graph rather than "Graph not supported"
+        assert!(matches!(
+            latest_sample(&data),
+            Some(PacketData::Float(_, _, _))
+        ));
+        assert_eq!(
+            latest_sample(&VecDeque::from([PacketData::Gap(0, SampleTime::now())])),
+            None
+        );
+    }
 }
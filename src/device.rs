@@ -0,0 +1,264 @@
+/*
+ *  Device descriptors
+ *
+ *  When the GUI connects it sends a hello frame, firmware that supports the handshake replies
+ *  with a descriptor packet saying what it is, followed by a channel packet for every packet ID
+ *  it will send. Firmware that doesn't support it just ignores the hello.
+ *
+ *  Descriptor: | protocol version | name len | name ... | firmware len | firmware ... |
+ *  Channel:    | id | packet kind | min (f32) | max (f32) | name len | name ... | unit len | unit ... |
+ *  A channel with min and max both NaN has no expected range, the range is in scaled units.
+ *
+ *  Firmware can also send a metadata packet at any time, with or without the handshake, to name a
+ *  packet ID and give its values a unit and a linear scaling. The packet's own ID is the channel.
+ *  Metadata:   | scale (f32) | offset (f32) | name len | name ... | unit len | unit ... |
+ *  Values are shown as raw * scale + offset, the stored data is left as it was received.
+ */
+
+use crate::arduino::{PacketError, PacketKind};
+use crate::data_window::DisplayType;
+
+/// Sent by the GUI to ask for the descriptor, and sent back by the firmware with the descriptor
+pub const KIND_DESCRIPTOR: u8 = 17;
+pub const KIND_CHANNEL: u8 = 18;
+pub const KIND_METADATA: u8 = 19;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceDescriptor {
+    pub name: String,
+    pub firmware_version: String,
+    pub protocol_version: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelDescriptor {
+    pub id: u8,
+    pub name: String,
+    pub kind: PacketKind,
+    pub unit: String,
+    pub range: Option<(f64, f64)>,
+}
+
+impl DeviceDescriptor {
+    pub fn parse(payload: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = PayloadReader::new(payload);
+        Ok(Self {
+            protocol_version: reader.u8()?,
+            name: reader.string()?,
+            firmware_version: reader.string()?,
+        })
+    }
+}
+
+impl ChannelDescriptor {
+    pub fn parse(payload: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = PayloadReader::new(payload);
+        let id = reader.u8()?;
+        let kind = reader.u8()?.into();
+        let (min, max) = (reader.f32()?, reader.f32()?);
+        Ok(Self {
+            id,
+            kind,
+            range: match min.is_nan() || max.is_nan() {
+                true => None,
+                false => Some((min as f64, max as f64)),
+            },
+            name: reader.string()?,
+            unit: reader.string()?,
+        })
+    }
+
+    /// The best way to show the channel's data
+    pub fn display_type(&self) -> DisplayType {
+        match self.kind {
+            PacketKind::String => DisplayType::Text,
+            PacketKind::Binary => DisplayType::HexDump,
+            PacketKind::Unknown => DisplayType::NoDisplay,
+            _ => DisplayType::Graph,
+        }
+    }
+}
+
+/// Unit and linear scaling for a channel's values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scaling {
+    pub unit: String,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Default for Scaling {
+    fn default() -> Self {
+        Self {
+            unit: String::new(),
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl Scaling {
+    pub fn is_identity(&self) -> bool {
+        self.scale == 1.0 && self.offset == 0.0
+    }
+
+    pub fn apply(&self, raw: f64) -> f64 {
+        raw * self.scale + self.offset
+    }
+
+    /// Formats a value without its unit, unscaled values are shown exactly as received so
+    /// integers don't gain a decimal point
+    pub fn format_value<D: std::fmt::Display>(&self, raw: D, value: f64) -> String {
+        match self.is_identity() {
+            true => raw.to_string(),
+            false => self.apply(value).to_string(),
+        }
+    }
+
+    /// Adds the unit to already formatted values
+    pub fn with_unit(&self, text: String) -> String {
+        match self.unit.is_empty() {
+            true => text,
+            false => format!("{} {}", text, self.unit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMetadata {
+    pub id: u8,
+    pub name: String,
+    pub scaling: Scaling,
+}
+
+impl ChannelMetadata {
+    pub fn parse(id: u8, payload: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = PayloadReader::new(payload);
+        let (scale, offset) = (reader.f32()?, reader.f32()?);
+        let name = reader.string()?;
+        Ok(Self {
+            id,
+            name,
+            scaling: Scaling {
+                unit: reader.string()?,
+                scale: scale as f64,
+                offset: offset as f64,
+            },
+        })
+    }
+}
+
+impl From<&ChannelDescriptor> for ChannelMetadata {
+    fn from(channel: &ChannelDescriptor) -> Self {
+        Self {
+            id: channel.id,
+            name: channel.name.clone(),
+            scaling: Scaling {
+                unit: channel.unit.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Reads values from the front of a payload, erroring if the payload runs out
+struct PayloadReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], PacketError> {
+        if self.bytes.len() < count {
+            return Err(PacketError::InvalidPayload(
+                "Descriptor or metadata is cut short".to_owned(),
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn f32(&mut self) -> Result<f32, PacketError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A string prefixed by its length
+    fn string(&mut self) -> Result<String, PacketError> {
+        let len = self.u8()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::FieldType;
+
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = vec![text.len() as u8];
+        bytes.extend(text.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parses_descriptor() {
+        let mut payload = vec![1];
+        payload.extend(string("Crane"));
+        payload.extend(string("1.2.0"));
+        assert_eq!(
+            DeviceDescriptor::parse(&payload).unwrap(),
+            DeviceDescriptor {
+                name: "Crane".to_owned(),
+                firmware_version: "1.2.0".to_owned(),
+                protocol_version: 1,
+            }
+        );
+        assert!(DeviceDescriptor::parse(&payload[..4]).is_err());
+    }
+
+    #[test]
+    fn parses_channel() {
+        let mut payload = vec![2, 11];
+        payload.extend(0f32.to_le_bytes());
+        payload.extend(40f32.to_le_bytes());
+        payload.extend(string("Load"));
+        payload.extend(string("N"));
+        let channel = ChannelDescriptor::parse(&payload).unwrap();
+        assert_eq!(channel.kind, PacketKind::Integer(FieldType::I16));
+        assert_eq!(channel.range, Some((0.0, 40.0)));
+        assert_eq!(channel.unit, "N");
+        assert_eq!(channel.display_type(), DisplayType::Graph);
+
+        payload[2..6].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert_eq!(ChannelDescriptor::parse(&payload).unwrap().range, None);
+    }
+
+    #[test]
+    fn parses_and_applies_metadata() {
+        let mut payload = Vec::new();
+        payload.extend(0.5f32.to_le_bytes());
+        payload.extend((-10f32).to_le_bytes());
+        payload.extend(string("Speed"));
+        payload.extend(string("rpm"));
+        let metadata = ChannelMetadata::parse(4, &payload).unwrap();
+        assert_eq!(metadata.id, 4);
+        assert_eq!(metadata.name, "Speed");
+        assert_eq!(metadata.scaling.apply(100.0), 40.0);
+        assert_eq!(
+            metadata
+                .scaling
+                .with_unit(metadata.scaling.format_value(100, 100.0)),
+            "40 rpm"
+        );
+        // Without scaling the value is shown as it was sent
+        assert_eq!(Scaling::default().format_value(7, 7.0), "7");
+    }
+}
@@ -29,7 +29,13 @@ impl ErrorInfo {
             severity,
         }
     }
-    pub fn show(&mut self, ctx: &egui::Context) {
+    pub fn title(&self) -> &str {
+        &self.error_title
+    }
+
+    /// Returns false once a minimal error has been dismissed
+    pub fn show(&mut self, ctx: &egui::Context) -> bool {
+        let mut open = true;
         let window = egui::Window::new(format!("Error: {}", &self.error_title))
             .title_bar(true)
             .enabled(true)
@@ -37,16 +43,24 @@ impl ErrorInfo {
                 "{}{}",
                 self.error_title, self.error_message
             )));
-        window.show(ctx, |ui| self.ui(ctx, ui));
+        window.show(ctx, |ui| self.ui(ctx, ui, &mut open));
+        open
     }
 
-    fn ui(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+    fn ui(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, open: &mut bool) {
         ui.vertical_centered(|ui| {
             ui.label(&self.error_message);
             ui.label(format!("Severity: {:?}", &self.severity));
-            if ui.button("Close Program").clicked() {
-                match &self.severity {
-                    _ => ctx.send_viewport_cmd(egui::ViewportCommand::Close),
+            match self.severity {
+                ErrorSeverity::Minimal => {
+                    if ui.button("Dismiss").clicked() {
+                        *open = false;
+                    }
+                }
+                _ => {
+                    if ui.button("Close Program").clicked() {
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Close)
+                    }
                 }
             }
         });
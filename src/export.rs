@@ -0,0 +1,394 @@
+/*
+ *  CSV export
+ *
+ *  Writes channels out for reports. Long form has a row per sample (time, channel, value), wide
+ *  form has a column per channel with a row for every time any of them has a sample, the other
+ *  channels are left empty or filled in by interpolating between their samples. Nothing is ever
+ *  interpolated across a gap where the connection was lost, or past a channel's first or last
+ *  sample.
+ *
+ *  Values are scaled & labelled with the channel's unit the same way the windows show them, each
+ *  field of a vector gets a column of its own.
+ */
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::Write,
+};
+
+use crate::arduino::PacketData;
+use crate::clock::{format_utc, unix_secs};
+use crate::device::Scaling;
+use crate::store::ChannelKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum CsvForm {
+    Long, // time, channel, value
+    Wide, // time, then a column per channel
+}
+
+/// How wide form fills in a channel that has no sample at a row's time
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Interpolation {
+    None,     // Leave it empty
+    Previous, // The last value before it
+    Linear,   // Straight line between the samples either side, text keeps the last value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Timestamps {
+    Relative, // Seconds since the start of the export
+    Absolute, // UTC date & time
+}
+
+/// The choices that are remembered between exports
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub form: CsvForm,
+    pub interpolation: Interpolation,
+    pub timestamps: Timestamps,
+    pub dir: String, // Where exports are saved
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            form: CsvForm::Wide,
+            interpolation: Interpolation::None,
+            timestamps: Timestamps::Relative,
+            dir: "exports".to_owned(),
+        }
+    }
+}
+
+impl ExportOptions {
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("export_options")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Layout:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.form, CsvForm::Wide, "Column per channel");
+                    ui.radio_value(&mut self.form, CsvForm::Long, "Row per sample");
+                });
+                ui.end_row();
+
+                ui.label("Fill in:");
+                ui.add_enabled_ui(self.form == CsvForm::Wide, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.interpolation, Interpolation::None, "Nothing");
+                        ui.radio_value(
+                            &mut self.interpolation,
+                            Interpolation::Previous,
+                            "Previous",
+                        );
+                        ui.radio_value(&mut self.interpolation, Interpolation::Linear, "Linear");
+                    });
+                });
+                ui.end_row();
+
+                ui.label("Timestamps:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.timestamps, Timestamps::Relative, "Seconds");
+                    ui.radio_value(&mut self.timestamps, Timestamps::Absolute, "UTC");
+                });
+                ui.end_row();
+
+                ui.label("Save in:");
+                ui.text_edit_singleline(&mut self.dir);
+                ui.end_row();
+            });
+    }
+}
+
+/// One exported value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+/// One column of the export, a channel or one field of a vector channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub points: Vec<(f64, Option<Value>)>, // Unix seconds & value, None where there's a gap
+}
+
+/// Splits a channel's samples into columns, sorted by time
+pub fn channel_columns(
+    name: &str,
+    scaling: &Scaling,
+    samples: &VecDeque<PacketData>,
+) -> Vec<Column> {
+    let mut columns: Vec<Column> = Vec::new();
+    for sample in samples {
+        let Some(time) = sample.time() else {
+            continue;
+        };
+        let t = unix_secs(time.wall);
+        let values = match sample {
+            PacketData::Gap(_, _) => {
+                for column in columns.iter_mut() {
+                    column.points.push((t, None));
+                }
+                continue;
+            }
+            PacketData::String(text, _, _) => vec![Value::Text(text.clone())],
+            PacketData::Binary(bytes, _, _) => vec![Value::Text(
+                bytes.iter().map(|b| format!("{:02X}", b)).collect(),
+            )],
+            _ => match sample.values() {
+                Some(values) => values
+                    .into_iter()
+                    .map(|v| Value::Number(scaling.apply(v)))
+                    .collect(),
+                None => continue,
+            },
+        };
+        // Vectors get a column for each field, named as they first turn up
+        while columns.len() < values.len() {
+            columns.push(Column {
+                name: String::new(),
+                points: Vec::new(),
+            });
+        }
+        for (column, value) in columns.iter_mut().zip(values) {
+            column.points.push((t, Some(value)));
+        }
+    }
+    let unit = match scaling.unit.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", scaling.unit),
+    };
+    let count = columns.len();
+    for (i, column) in columns.iter_mut().enumerate() {
+        column.name = match count {
+            1 => format!("{}{}", name, unit),
+            _ => format!("{}[{}]{}", name, i, unit),
+        };
+        column
+            .points
+            .sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    }
+    columns
+}
+
+/// The first & last time of all the columns, in unix seconds
+pub fn time_span(columns: &[Column]) -> Option<(f64, f64)> {
+    let first = columns.iter().filter_map(|c| c.points.first()).map(|p| p.0);
+    let last = columns.iter().filter_map(|c| c.points.last()).map(|p| p.0);
+    Some((first.reduce(f64::min)?, last.reduce(f64::max)?))
+}
+
+/// Writes the columns as CSV, only the points within the range (unix seconds) are written.
+/// Returns the number of rows written, not counting the header.
+pub fn write_csv(
+    out: &mut impl Write,
+    columns: &[Column],
+    options: &ExportOptions,
+    range: Option<(f64, f64)>,
+) -> std::io::Result<usize> {
+    let in_range = |t: f64| range.map_or(true, |(from, to)| t >= from && t <= to);
+    let Some(span) = time_span(columns) else {
+        writeln!(out, "time")?;
+        return Ok(0);
+    };
+    let origin = range.map_or(span.0, |(from, _)| from.max(span.0));
+    let format_time = |t: f64| match options.timestamps {
+        Timestamps::Relative => format!("{:.6}", t - origin),
+        Timestamps::Absolute => format_utc(t),
+    };
+    let mut rows = 0;
+    match options.form {
+        CsvForm::Long => {
+            writeln!(out, "time,channel,value")?;
+            let mut points: Vec<(f64, &str, &Value)> = columns
+                .iter()
+                .flat_map(|c| {
+                    c.points
+                        .iter()
+                        .filter_map(move |(t, v)| Some((*t, c.name.as_str(), v.as_ref()?)))
+                })
+                .filter(|(t, _, _)| in_range(*t))
+                .collect();
+            // Stable, so samples at the same time stay in column order
+            points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            for (t, name, value) in points {
+                writeln!(
+                    out,
+                    "{},{},{}",
+                    format_time(t),
+                    escape(name),
+                    format_value(value)
+                )?;
+                rows += 1;
+            }
+        }
+        CsvForm::Wide => {
+            let names: Vec<String> = columns.iter().map(|c| escape(&c.name)).collect();
+            writeln!(out, "time,{}", names.join(","))?;
+            let times: BTreeSet<u64> = columns
+                .iter()
+                .flat_map(|c| c.points.iter().filter(|p| p.1.is_some()).map(|p| p.0))
+                .filter(|t| in_range(*t))
+                .map(f64::to_bits) // Positive floats sort the same as their bits
+                .collect();
+            for t in times.into_iter().map(f64::from_bits) {
+                let values: Vec<String> = columns
+                    .iter()
+                    .map(|c| {
+                        value_at(c, t, options.interpolation)
+                            .as_ref()
+                            .map(format_value)
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                writeln!(out, "{},{}", format_time(t), values.join(","))?;
+                rows += 1;
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// The column's value at the time, either its own sample or interpolated from either side
+fn value_at(column: &Column, t: f64, interpolation: Interpolation) -> Option<Value> {
+    let points = &column.points;
+    let i = points.partition_point(|(time, _)| *time < t);
+    // The last point at the time wins, a gap at the same time as a sample comes after it
+    let exact = points[i..].iter().take_while(|(time, _)| *time == t).last();
+    if let Some((_, value)) = exact {
+        return value.clone();
+    }
+    let (before_t, before) = points.get(i.checked_sub(1)?)?;
+    let (after_t, after) = points.get(i)?;
+    match (interpolation, before.as_ref()?, after.as_ref()?) {
+        (Interpolation::None, _, _) => None,
+        (Interpolation::Linear, Value::Number(a), Value::Number(b)) => {
+            let fraction = (t - before_t) / (after_t - before_t);
+            Some(Value::Number(a + (b - a) * fraction))
+        }
+        (Interpolation::Previous | Interpolation::Linear, before, _) => Some(before.clone()),
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Number(n) => n.to_string(),
+        Value::Text(text) => escape(text),
+    }
+}
+
+/// Quotes the field if it needs it
+fn escape(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+/// Name for a new export, "export-YYYY-MM-DD_HH-MM-SS.csv"
+pub fn new_export_name() -> String {
+    let now = unix_secs(std::time::SystemTime::now());
+    let stamp = format_utc(now)[..19].replace(' ', "_").replace(':', "-");
+    format!("export-{}.csv", stamp)
+}
+
+/// Name for a channel in the export list & column headers
+pub fn channel_label((device, id): &ChannelKey, name: Option<&str>) -> String {
+    match name {
+        Some(name) if !name.is_empty() => format!("{} {}", device, name),
+        _ => format!("{} ID {}", device, id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SampleTime;
+
+    fn export(columns: &[Column], options: &ExportOptions, range: Option<(f64, f64)>) -> String {
+        let mut out = Vec::new();
+        write_csv(&mut out, columns, options, range).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn sample_columns() -> Vec<Column> {
+        let load = VecDeque::from([
+            PacketData::Float(1.0, 1, SampleTime::at_wall(1000.0)),
+            PacketData::Float(3.0, 1, SampleTime::at_wall(1002.0)),
+            PacketData::Gap(1, SampleTime::at_wall(1002.5)),
+            PacketData::Float(5.0, 1, SampleTime::at_wall(1004.0)),
+        ]);
+        let state = VecDeque::from([
+            PacketData::String("lift, slow".to_owned(), 2, SampleTime::at_wall(1001.0)),
+            PacketData::String("hold".to_owned(), 2, SampleTime::at_wall(1003.0)),
+        ]);
+        let scaling = Scaling {
+            unit: "kN".to_owned(),
+            scale: 2.0,
+            offset: 0.0,
+        };
+        let mut columns = channel_columns("Load", &scaling, &load);
+        columns.extend(channel_columns("State", &Scaling::default(), &state));
+        columns
+    }
+
+    #[test]
+    fn writes_long_form() {
+        let options = ExportOptions {
+            form: CsvForm::Long,
+            ..Default::default()
+        };
+        assert_eq!(
+            export(&sample_columns(), &options, Some((1000.5, 1003.5))),
+            "time,channel,value\n\
+             0.500000,State,\"lift, slow\"\n\
+             1.500000,Load (kN),6\n\
+             2.500000,State,hold\n"
+        );
+    }
+
+    #[test]
+    fn interpolates_wide_form_but_not_across_gaps() {
+        let mut options = ExportOptions {
+            interpolation: Interpolation::Linear,
+            ..Default::default()
+        };
+        let columns = sample_columns();
+        assert_eq!(
+            export(&columns, &options, None),
+            "time,Load (kN),State\n\
+             0.000000,2,\n\
+             1.000000,4,\"lift, slow\"\n\
+             2.000000,6,\"lift, slow\"\n\
+             3.000000,,hold\n\
+             4.000000,10,\n"
+        );
+        options.interpolation = Interpolation::Previous;
+        options.timestamps = Timestamps::Absolute;
+        assert_eq!(
+            export(&columns, &options, Some((1001.0, 1002.0))),
+            "time,Load (kN),State\n\
+             1970-01-01 00:16:41.000,2,\"lift, slow\"\n\
+             1970-01-01 00:16:42.000,6,\"lift, slow\"\n"
+        );
+    }
+
+    #[test]
+    fn splits_vectors_into_columns() {
+        use crate::layout::FieldValue;
+        let samples = VecDeque::from([PacketData::Vector(
+            vec![FieldValue::Signed(-1), FieldValue::Float(0.5)],
+            3,
+            SampleTime::at_wall(1000.0),
+        )]);
+        let columns = channel_columns("Pos", &Scaling::default(), &samples);
+        assert_eq!(
+            export(&columns, &ExportOptions::default(), None),
+            "time,Pos[0],Pos[1]\n0.000000,-1,0.5\n"
+        );
+    }
+}
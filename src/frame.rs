@@ -0,0 +1,374 @@
+/*
+ *  Serial framing
+ *
+ *  Every packet sent by the Arduino is wrapped in a frame so that the GUI can find where a packet
+ *  starts and ends, no matter how the bytes are split up between reads.
+ *
+ *  +------+------+---------+------+----+-----+-------------+------------+
+ *  | 0xA5 | 0x5A | version | kind | id | len | payload ... | crc16 (LE) |
+ *  +------+------+---------+------+----+-----+-------------+------------+
+ *
+ *  The CRC is CRC-16/CCITT-FALSE and covers everything from the version byte to the end of the
+ *  payload. The sync bytes are not covered, they only exist to find the start of a frame.
+ *
+ *  The top two bits of the kind byte are flags. If either is set the first four bytes of the
+ *  payload are the Arduino's millis() or micros() when the packet was sent, little-endian.
+ */
+
+use std::fmt::Display;
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Sync bytes, version, kind, id & length
+pub const HEADER_LEN: usize = 6;
+pub const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+/// Set in the kind byte when the payload starts with millis()
+pub const FLAG_MILLIS: u8 = 0x80;
+/// Set in the kind byte when the payload starts with micros()
+pub const FLAG_MICROS: u8 = 0x40;
+pub const KIND_MASK: u8 = 0x3F;
+const DEVICE_TIME_LEN: usize = 4;
+
+/// The Arduino's clock when a packet was sent, these wrap around so see `clock::DeviceClock`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceTime {
+    Millis(u32),
+    Micros(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub version: u8,
+    pub kind: u8,
+    pub id: u8,
+    pub device_time: Option<DeviceTime>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// Bytes that were thrown away whilst looking for the start of a frame
+    Desync(usize),
+    /// The frame was sent using a protocol version we don't understand
+    UnsupportedVersion(u8),
+    /// The checksum in the frame does not match the frame contents
+    BadChecksum { expected: u16, found: u16 },
+    /// The kind says there's a device time but the payload is too short to hold one
+    MissingDeviceTime,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Desync(count) => write!(f, "Skipped {} bytes looking for a frame", count),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version {}", version)
+            }
+            Self::BadChecksum { expected, found } => write!(
+                f,
+                "Checksum mismatch (expected {:#06x}, found {:#06x})",
+                expected, found
+            ),
+            Self::MissingDeviceTime => write!(f, "Frame is too short to hold its device time"),
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(kind: u8, id: u8, payload: Vec<u8>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            kind,
+            id,
+            device_time: None,
+            payload,
+        }
+    }
+
+    pub fn with_device_time(mut self, device_time: DeviceTime) -> Self {
+        self.device_time = Some(device_time);
+        self
+    }
+
+    /// Wraps the frame in the sync bytes, header and checksum, ready to be written to serial.
+    /// Panics if the payload is longer than `MAX_PAYLOAD_LEN`
+    pub fn encode(&self) -> Vec<u8> {
+        let (flag, time) = match self.device_time {
+            None => (0, None),
+            Some(DeviceTime::Millis(ms)) => (FLAG_MILLIS, Some(ms)),
+            Some(DeviceTime::Micros(us)) => (FLAG_MICROS, Some(us)),
+        };
+        let payload_len = self.payload.len() + time.map_or(0, |_| DEVICE_TIME_LEN);
+        assert!(
+            payload_len <= MAX_PAYLOAD_LEN,
+            "Frame payload exceeds {} bytes!",
+            MAX_PAYLOAD_LEN
+        );
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload_len + CRC_LEN);
+        bytes.extend_from_slice(&SYNC);
+        bytes.push(self.version);
+        bytes.push((self.kind & KIND_MASK) | flag);
+        bytes.push(self.id);
+        bytes.push(payload_len as u8);
+        if let Some(time) = time {
+            bytes.extend_from_slice(&time.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        let crc = crc16(&bytes[SYNC.len()..]);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// CRC-16/CCITT-FALSE, the Arduino side uses the same algorithm so don't change one without the
+/// other
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Accumulates bytes from serial and splits them into frames. Bytes can be pushed in any size
+/// chunk, incomplete frames are kept until the rest of the frame arrives.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // Set after a bad frame so the bytes skipped to recover aren't reported a second time
+    resyncing: bool,
+    keep_skipped: bool,
+    skipped: Vec<u8>, // Bytes between frames, only kept if asked for
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds bytes read from serial to the end of the buffer
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Keeps the bytes found between frames, such as text printed by the sketch, until they're
+    /// taken with `take_skipped`
+    pub fn keep_skipped(&mut self, keep: bool) {
+        self.keep_skipped = keep;
+    }
+
+    /// The bytes skipped between frames since this was last called, oldest first
+    pub fn take_skipped(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// Returns the next frame or frame error in the buffer, returns None once more bytes are
+    /// needed
+    pub fn next_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        loop {
+            let start = match find_sync(&self.buffer) {
+                Some(start) => start,
+                None => {
+                    // The final byte may be the first half of the sync bytes, keep it around
+                    let keep = usize::from(self.buffer.last() == Some(&SYNC[0]));
+                    let skipped = self.buffer.len() - keep;
+                    self.skip(skipped);
+                    return self.desync(skipped);
+                }
+            };
+            if start > 0 {
+                self.skip(start);
+                match self.desync(start) {
+                    Some(err) => return Some(err),
+                    None => continue,
+                }
+            }
+
+            if self.buffer.len() < HEADER_LEN {
+                return None;
+            }
+            let version = self.buffer[2];
+            if version != PROTOCOL_VERSION {
+                self.skip_frame();
+                return Some(Err(FrameError::UnsupportedVersion(version)));
+            }
+            let payload_len = self.buffer[5] as usize;
+            let frame_len = HEADER_LEN + payload_len + CRC_LEN;
+            if self.buffer.len() < frame_len {
+                return None;
+            }
+
+            let crc_start = HEADER_LEN + payload_len;
+            let expected = crc16(&self.buffer[SYNC.len()..crc_start]);
+            let found = u16::from_le_bytes([self.buffer[crc_start], self.buffer[crc_start + 1]]);
+            if expected != found {
+                self.skip_frame();
+                return Some(Err(FrameError::BadChecksum { expected, found }));
+            }
+
+            let kind = self.buffer[3];
+            let id = self.buffer[4];
+            let mut payload = self.buffer[HEADER_LEN..crc_start].to_vec();
+            self.buffer.drain(..frame_len);
+            self.resyncing = false;
+
+            let device_time = match kind & (FLAG_MILLIS | FLAG_MICROS) {
+                0 => None,
+                flag => {
+                    if payload.len() < DEVICE_TIME_LEN {
+                        return Some(Err(FrameError::MissingDeviceTime));
+                    }
+                    let time_bytes: Vec<u8> = payload.drain(..DEVICE_TIME_LEN).collect();
+                    let time = u32::from_le_bytes(time_bytes.try_into().unwrap());
+                    match flag {
+                        FLAG_MILLIS => Some(DeviceTime::Millis(time)),
+                        _ => Some(DeviceTime::Micros(time)),
+                    }
+                }
+            };
+            return Some(Ok(Frame {
+                version,
+                kind: kind & KIND_MASK,
+                id,
+                device_time,
+                payload,
+            }));
+        }
+    }
+
+    /// Drops the first sync byte so the search for the next frame starts just after it, the
+    /// "frame" may have been payload bytes that happened to look like a sync
+    fn skip_frame(&mut self) {
+        self.skip(1);
+        self.resyncing = true;
+    }
+
+    /// Drops bytes from the front of the buffer that aren't part of a frame
+    fn skip(&mut self, count: usize) {
+        let skipped = self.buffer.drain(..count);
+        if self.keep_skipped {
+            self.skipped.extend(skipped);
+        }
+    }
+
+    fn desync(&mut self, skipped: usize) -> Option<Result<Frame, FrameError>> {
+        if skipped == 0 || self.resyncing {
+            return None;
+        }
+        Some(Err(FrameError::Desync(skipped)))
+    }
+}
+
+fn find_sync(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(SYNC.len()).position(|w| w == SYNC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Result<Frame, FrameError>> {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(bytes);
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn crc_matches_ccitt_false() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn encodes_header_and_checksum() {
+        let bytes = Frame::new(1, 7, b"hi".to_vec()).encode();
+        assert_eq!(bytes[..HEADER_LEN], [0xA5, 0x5A, PROTOCOL_VERSION, 1, 7, 2]);
+        assert_eq!(bytes.len(), HEADER_LEN + 2 + CRC_LEN);
+        let crc = crc16(&bytes[SYNC.len()..HEADER_LEN + 2]);
+        assert_eq!(bytes[HEADER_LEN + 2..], crc.to_le_bytes());
+    }
+
+    #[test]
+    fn decodes_across_pushes() {
+        let frame = Frame::new(2, 3, vec![0xA5, 0x5A, 0x00]); // Payload that looks like a sync
+        let bytes = frame.encode();
+        let mut decoder = FrameDecoder::new();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.push(&[*byte]);
+            assert_eq!(decoder.next_frame(), None);
+        }
+        decoder.push(&bytes[bytes.len() - 1..]);
+        assert_eq!(decoder.next_frame(), Some(Ok(frame)));
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let frame = Frame::new(1, 1, b"ok".to_vec());
+        let mut bytes = b"junk".to_vec();
+        bytes.extend(frame.encode());
+        assert_eq!(decode(&bytes), vec![Err(FrameError::Desync(4)), Ok(frame)]);
+    }
+
+    #[test]
+    fn keeps_a_trailing_sync_byte() {
+        let frame = Frame::new(1, 1, b"ok".to_vec());
+        let bytes = frame.encode();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0x00, bytes[0]]);
+        assert_eq!(decoder.next_frame(), Some(Err(FrameError::Desync(1))));
+        decoder.push(&bytes[1..]);
+        assert_eq!(decoder.next_frame(), Some(Ok(frame)));
+    }
+
+    #[test]
+    fn reports_bad_checksum_and_recovers() {
+        let good = Frame::new(1, 2, b"fine".to_vec());
+        let mut corrupt = Frame::new(1, 1, b"bad".to_vec()).encode();
+        corrupt[HEADER_LEN] ^= 0xFF;
+        let mut bytes = corrupt;
+        bytes.extend(good.encode());
+        let frames = decode(&bytes);
+        assert!(matches!(frames[0], Err(FrameError::BadChecksum { .. })));
+        // The rest of the corrupt frame is skipped without another error
+        assert_eq!(frames[1..], [Ok(good)]);
+    }
+
+    #[test]
+    fn keeps_every_byte_outside_good_frames() {
+        let good = Frame::new(1, 2, b"fine".to_vec());
+        let mut corrupt = Frame::new(1, 1, b"bad".to_vec()).encode();
+        corrupt[HEADER_LEN] ^= 0xFF;
+        let mut skipped = b"boot\r\n".to_vec();
+        skipped.extend(&corrupt);
+        skipped.extend(b"\r\nready\r\n");
+        let mut bytes = skipped[..skipped.len() - 7].to_vec();
+        bytes.extend(good.encode());
+        bytes.extend(&skipped[skipped.len() - 7..]);
+        let mut decoder = FrameDecoder::new();
+        decoder.keep_skipped(true);
+        decoder.push(&bytes);
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame()).collect();
+        assert!(frames.contains(&Ok(good)));
+        // The text either side & the whole corrupt frame, even its sync byte, only the good frame goes
+        assert_eq!(decoder.take_skipped(), skipped);
+    }
+
+    #[test]
+    fn reports_unsupported_version() {
+        let mut bytes = Frame::new(1, 1, vec![]).encode();
+        bytes[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            decode(&bytes),
+            vec![Err(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1))]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+/*
+ *  CSV import
+ *
+ *  Reads reference data, such as the expected load curve from a spreadsheet, as channels of
+ *  their own so they can be opened & overlaid on live data. Each column is mapped to the time, a
+ *  channel or nothing. Times can be seconds, milliseconds or dates & times, or there can be no
+ *  time column at all and the rows are a fixed interval apart.
+ *
+ *  Imported channels always start at the moment they're imported, from the earliest time in the
+ *  file whichever row it's on, so they line up with live data straight away and can be shifted
+ *  from the window.
+ */
+
+use std::{collections::VecDeque, fmt::Display, path::Path};
+
+use crate::arduino::PacketData;
+use crate::clock::{parse_utc, SampleTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum TimeFormat {
+    Seconds,
+    Milliseconds,
+    DateTime, // "YYYY-MM-DD HH:MM:SS.fff" or just "HH:MM:SS.fff"
+    None,     // No time column, the rows are `ImportOptions::interval` apart
+}
+
+/// The choices that are remembered between imports
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    pub delimiter: char,
+    pub header: bool, // The first row names the columns
+    pub time_format: TimeFormat,
+    pub interval: f64, // Seconds between rows without a time column
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            header: true,
+            time_format: TimeFormat::Seconds,
+            interval: 1.0,
+        }
+    }
+}
+
+impl ImportOptions {
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("import_options")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Separator:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.delimiter, ',', "Comma");
+                    ui.radio_value(&mut self.delimiter, ';', "Semicolon");
+                    ui.radio_value(&mut self.delimiter, '\t', "Tab");
+                });
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(&mut self.header, "First row is column names");
+                ui.end_row();
+
+                ui.label("Times:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.time_format, TimeFormat::Seconds, "Seconds");
+                    ui.radio_value(&mut self.time_format, TimeFormat::Milliseconds, "ms");
+                    ui.radio_value(&mut self.time_format, TimeFormat::DateTime, "Date & time");
+                    ui.radio_value(&mut self.time_format, TimeFormat::None, "Every");
+                    ui.add_enabled(
+                        self.time_format == TimeFormat::None,
+                        egui::DragValue::new(&mut self.interval)
+                            .speed(0.01)
+                            .clamp_range(0.000_001..=f64::MAX)
+                            .suffix(" s"),
+                    );
+                });
+                ui.end_row();
+            });
+    }
+}
+
+/// What a column is used for
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnRole {
+    Skip,
+    Time,
+    Channel { name: String, unit: String },
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(std::io::Error),
+    Empty,
+    NoChannels,
+    TooManyChannels(usize),
+    BadTime { row: usize, text: String }, // Row number as shown in a spreadsheet
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Empty => write!(f, "The file has no rows"),
+            Self::NoChannels => write!(f, "No columns are imported as channels"),
+            Self::TooManyChannels(n) => write!(f, "{} channels, at most 256 can be imported", n),
+            Self::BadTime { row, text } => write!(f, "Row {}: '{}' is not a time", row, text),
+        }
+    }
+}
+
+impl From<std::io::Error> for ImportError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A channel read from the file, IDs are the channel's position among the imported columns
+#[derive(Debug, Clone)]
+pub struct ImportedChannel {
+    pub id: u8,
+    pub name: String,
+    pub unit: String,
+    pub samples: VecDeque<PacketData>, // Oldest first
+}
+
+/// The file split into rows & cells
+pub fn read_rows(path: &Path, delimiter: char) -> Result<Vec<Vec<String>>, ImportError> {
+    let text = std::fs::read_to_string(path)?;
+    let rows = parse_rows(&text, delimiter);
+    match rows.is_empty() {
+        true => Err(ImportError::Empty),
+        false => Ok(rows),
+    }
+}
+
+/// Splits CSV text into cells, quoted cells can hold the delimiter, quotes & new lines. Blank
+/// lines are skipped.
+pub fn parse_rows(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cell.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if quoted => cell.push(c),
+            c if c == delimiter => row.push(std::mem::take(&mut cell)),
+            '\r' => (),
+            '\n' => {
+                row.push(std::mem::take(&mut cell));
+                if row.iter().any(|cell| !cell.is_empty()) {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+            }
+            c => cell.push(c),
+        }
+    }
+    row.push(cell);
+    if row.iter().any(|cell| !cell.is_empty()) {
+        rows.push(row);
+    }
+    rows
+}
+
+/// A guess at what each column is, the first column is the time if the file has times and the
+/// columns that hold numbers are channels
+pub fn guess_roles(rows: &[Vec<String>], options: &ImportOptions) -> Vec<ColumnRole> {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let (names, data) = match options.header {
+        true => (rows.first(), rows.get(1)),
+        false => (None, rows.first()),
+    };
+    (0..width)
+        .map(|i| {
+            if i == 0 && options.time_format != TimeFormat::None {
+                return ColumnRole::Time;
+            }
+            let numeric = data
+                .and_then(|row| row.get(i))
+                .is_some_and(|cell| cell.trim().parse::<f64>().is_ok());
+            match numeric {
+                true => ColumnRole::Channel {
+                    name: names
+                        .and_then(|row| row.get(i))
+                        .map(|name| name.trim().to_owned())
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or_else(|| format!("Column {}", i + 1)),
+                    unit: String::new(),
+                },
+                false => ColumnRole::Skip,
+            }
+        })
+        .collect()
+}
+
+/// How far apart times in one file can be, about a century
+const MAX_SPAN: f64 = 100.0 * 365.25 * 86_400.0;
+
+/// Reads the channels out of the rows, the earliest sample is taken at `start`. Cells that aren't
+/// numbers are left out of their channel.
+pub fn import_channels(
+    rows: &[Vec<String>],
+    roles: &[ColumnRole],
+    options: &ImportOptions,
+    start: SampleTime,
+) -> Result<Vec<ImportedChannel>, ImportError> {
+    let time_column = roles.iter().position(|role| *role == ColumnRole::Time);
+    let mut channels: Vec<(usize, ImportedChannel)> = Vec::new();
+    for (i, role) in roles.iter().enumerate() {
+        if let ColumnRole::Channel { name, unit } = role {
+            channels.push((
+                i,
+                ImportedChannel {
+                    id: channels.len() as u8,
+                    name: name.clone(),
+                    unit: unit.clone(),
+                    samples: VecDeque::new(),
+                },
+            ));
+        }
+    }
+    match channels.len() {
+        0 => return Err(ImportError::NoChannels),
+        n if n > 256 => return Err(ImportError::TooManyChannels(n)),
+        _ => (),
+    }
+    let skip = usize::from(options.header);
+    let mut times = Vec::new();
+    for (i, row) in rows.iter().enumerate().skip(skip) {
+        let seconds = match (options.time_format, time_column) {
+            (TimeFormat::None, _) | (_, None) => (i - skip) as f64 * options.interval,
+            (format, Some(column)) => {
+                let text = row.get(column).map_or("", |cell| cell.trim());
+                let seconds = match format {
+                    TimeFormat::Seconds => text.parse().ok(),
+                    TimeFormat::Milliseconds => text.parse::<f64>().ok().map(|ms| ms / 1000.0),
+                    _ => parse_utc(text),
+                };
+                // Far from the first row is a typo or the wrong format, rather than real data
+                let first = times.first().copied().unwrap_or(0.0);
+                seconds
+                    .filter(|s| s.is_finite())
+                    .filter(|s| times.is_empty() || (s - first).abs() < MAX_SPAN)
+                    .ok_or(ImportError::BadTime {
+                        row: i + 1,
+                        text: text.to_owned(),
+                    })?
+            }
+        };
+        times.push(seconds);
+    }
+    // The earliest row is the start, even if it isn't the first
+    let origin = times.iter().copied().fold(f64::INFINITY, f64::min);
+    for (row, seconds) in rows.iter().skip(skip).zip(times) {
+        let time = start.shifted(seconds - origin);
+        for (column, channel) in channels.iter_mut() {
+            let cell = row.get(*column).map_or("", |cell| cell.trim());
+            if let Ok(value) = cell.parse::<f64>() {
+                channel
+                    .samples
+                    .push_back(PacketData::Float(value, channel.id, time));
+            }
+        }
+    }
+    // Spreadsheets aren't always in order
+    let mut channels: Vec<ImportedChannel> = channels.into_iter().map(|(_, c)| c).collect();
+    for channel in channels.iter_mut() {
+        channel
+            .samples
+            .make_contiguous()
+            .sort_by_key(|sample| sample.time().map(|t| t.host));
+    }
+    Ok(channels)
+}
+
+/// The name imported channels are shown under
+pub fn import_device(path: &Path) -> String {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    format!("{} (import)", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(channel: &ImportedChannel, start: &SampleTime) -> Vec<(f64, f64)> {
+        channel
+            .samples
+            .iter()
+            .map(|sample| match sample {
+                PacketData::Float(value, _, time) => {
+                    (time.host.duration_since(start.host).as_secs_f64(), *value)
+                }
+                other => panic!("{:?} was imported", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_quoted_cells() {
+        let rows = parse_rows("a,\"b, \"\"c\"\"\"\r\n\n1,\"two\nlines\"", ',');
+        assert_eq!(
+            rows,
+            vec![
+                vec!["a".to_owned(), "b, \"c\"".to_owned()],
+                vec!["1".to_owned(), "two\nlines".to_owned()]
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_mapped_columns() {
+        let rows = parse_rows(
+            "Time;Load;Note;Angle\n10.0;1.5;start;0\n10.5;;;2\n11.0;3;end;4\n",
+            ';',
+        );
+        let options = ImportOptions {
+            delimiter: ';',
+            ..Default::default()
+        };
+        let mut roles = guess_roles(&rows, &options);
+        assert_eq!(roles[0], ColumnRole::Time);
+        assert_eq!(roles[2], ColumnRole::Skip);
+        roles[1] = ColumnRole::Channel {
+            name: "Load".to_owned(),
+            unit: "kN".to_owned(),
+        };
+        let start = SampleTime::now();
+        let channels = import_channels(&rows, &roles, &options, start).unwrap();
+        assert_eq!(channels.len(), 2);
+        assert_eq!((channels[0].id, channels[0].unit.as_str()), (0, "kN"));
+        assert_eq!(values(&channels[0], &start), vec![(0.0, 1.5), (1.0, 3.0)]);
+        assert_eq!(channels[1].name, "Angle");
+        assert_eq!(
+            values(&channels[1], &start),
+            vec![(0.0, 0.0), (0.5, 2.0), (1.0, 4.0)]
+        );
+    }
+
+    #[test]
+    fn reads_date_times_and_fixed_intervals() {
+        let rows = parse_rows("2024-05-01 09:00:00,1\n2024-05-01 09:00:02.5,2\n", ',');
+        let mut options = ImportOptions {
+            header: false,
+            time_format: TimeFormat::DateTime,
+            ..Default::default()
+        };
+        let roles = guess_roles(&rows, &options);
+        let start = SampleTime::now();
+        let channels = import_channels(&rows, &roles, &options, start).unwrap();
+        assert_eq!(values(&channels[0], &start), vec![(0.0, 1.0), (2.5, 2.0)]);
+
+        options.time_format = TimeFormat::None;
+        options.interval = 0.25;
+        let roles = vec![ColumnRole::Skip, guess_roles(&rows, &options).remove(1)];
+        let channels = import_channels(&rows, &roles, &options, start).unwrap();
+        assert_eq!(values(&channels[0], &start), vec![(0.0, 1.0), (0.25, 2.0)]);
+
+        options.time_format = TimeFormat::Seconds;
+        let roles = vec![ColumnRole::Time, roles[1].clone()];
+        assert!(matches!(
+            import_channels(&rows, &roles, &options, start),
+            Err(ImportError::BadTime { row: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn starts_from_the_earliest_row() {
+        let rows = parse_rows("12,2\n10,1\n13.5,3\n", ',');
+        let options = ImportOptions {
+            header: false,
+            ..Default::default()
+        };
+        let roles = vec![ColumnRole::Time, guess_roles(&rows, &options).remove(1)];
+        let start = SampleTime::now();
+        let channels = import_channels(&rows, &roles, &options, start).unwrap();
+        assert_eq!(
+            values(&channels[0], &start),
+            vec![(0.0, 1.0), (2.0, 2.0), (3.5, 3.0)]
+        );
+    }
+
+    #[test]
+    fn rejects_times_out_of_range() {
+        let options = ImportOptions {
+            header: false,
+            ..Default::default()
+        };
+        for text in ["0,1\n1e20,2\n", "-1e300,1\n1e300,2\n"] {
+            let rows = parse_rows(text, ',');
+            let roles = vec![ColumnRole::Time, guess_roles(&rows, &options).remove(1)];
+            assert!(matches!(
+                import_channels(&rows, &roles, &options, SampleTime::now()),
+                Err(ImportError::BadTime { row: 2, .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ *  Struct layouts
+ *
+ *  Lets a binary packet be read as a packed C struct. The layout is written the same way the
+ *  struct would be declared on the Arduino, one field per entry separated by commas or new lines:
+ *      u8 direction, i16 revolutions, f32 newtons
+ *  All fields are little-endian as that's what the AVR and ARM boards use.
+ */
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl FieldType {
+    /// Number of bytes the field takes up in the packed struct
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 | Self::F32 => 4,
+            Self::U64 | Self::I64 | Self::F64 => 8,
+        }
+    }
+
+    /// Reads the field from the start of the given bytes, None if there aren't enough bytes
+    pub fn read(&self, bytes: &[u8]) -> Option<FieldValue> {
+        let bytes = bytes.get(..self.size())?;
+        Some(match self {
+            Self::U8 => FieldValue::Unsigned(bytes[0] as u64),
+            Self::I8 => FieldValue::Signed(bytes[0] as i8 as i64),
+            Self::U16 => FieldValue::Unsigned(u16::from_le_bytes(bytes.try_into().ok()?) as u64),
+            Self::I16 => FieldValue::Signed(i16::from_le_bytes(bytes.try_into().ok()?) as i64),
+            Self::U32 => FieldValue::Unsigned(u32::from_le_bytes(bytes.try_into().ok()?) as u64),
+            Self::I32 => FieldValue::Signed(i32::from_le_bytes(bytes.try_into().ok()?) as i64),
+            Self::U64 => FieldValue::Unsigned(u64::from_le_bytes(bytes.try_into().ok()?)),
+            Self::I64 => FieldValue::Signed(i64::from_le_bytes(bytes.try_into().ok()?)),
+            Self::F32 => FieldValue::Float(f32::from_le_bytes(bytes.try_into().ok()?) as f64),
+            Self::F64 => FieldValue::Float(f64::from_le_bytes(bytes.try_into().ok()?)),
+        })
+    }
+}
+
+impl TryFrom<&str> for FieldType {
+    type Error = String;
+
+    /// Accepts both the short names (u8, f32) and the Arduino names (uint8_t, float)
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "u8" | "uint8_t" | "byte" | "bool" => Ok(Self::U8),
+            "i8" | "int8_t" | "char" => Ok(Self::I8),
+            "u16" | "uint16_t" => Ok(Self::U16),
+            "i16" | "int16_t" => Ok(Self::I16),
+            "u32" | "uint32_t" => Ok(Self::U32),
+            "i32" | "int32_t" => Ok(Self::I32),
+            "u64" | "uint64_t" => Ok(Self::U64),
+            "i64" | "int64_t" => Ok(Self::I64),
+            "f32" | "float" => Ok(Self::F32),
+            "f64" | "double" => Ok(Self::F64),
+            _ => Err(format!("Unknown field type '{}'", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+}
+
+impl FieldValue {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Self::Unsigned(v) => *v as f64,
+            Self::Signed(v) => *v as f64,
+            Self::Float(v) => *v,
+        }
+    }
+}
+
+impl Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsigned(v) => write!(f, "{}", v),
+            Self::Signed(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub field_type: FieldType,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StructLayout {
+    pub fields: Vec<Field>,
+}
+
+impl StructLayout {
+    /// Parses a layout such as "u8 direction, i16 revolutions", trailing semicolons are allowed so
+    /// a struct body can be pasted straight from a sketch
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut fields = Vec::new();
+        for entry in text.split([',', '\n', ';']) {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let mut parts = entry.split_whitespace();
+            let field_type = FieldType::try_from(parts.next().unwrap_or_default())?;
+            let name = match (parts.next(), parts.next()) {
+                (Some(name), None) => name.to_owned(),
+                (None, _) => format!("field{}", fields.len()),
+                (Some(_), Some(_)) => return Err(format!("Could not read field '{}'", entry)),
+            };
+            fields.push(Field { name, field_type });
+        }
+        if fields.is_empty() {
+            return Err("Layout has no fields".to_owned());
+        }
+        Ok(Self { fields })
+    }
+
+    /// Total size of the packed struct in bytes
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|f| f.field_type.size()).sum()
+    }
+
+    /// Reads each field in order, stopping at the first field that doesn't fit in the bytes
+    pub fn decode<'a>(&'a self, bytes: &[u8]) -> Vec<(&'a str, FieldValue)> {
+        let mut values = Vec::new();
+        let mut offset = 0;
+        for field in &self.fields {
+            match field.field_type.read(&bytes[offset.min(bytes.len())..]) {
+                Some(value) => values.push((field.name.as_str(), value)),
+                None => break,
+            }
+            offset += field.field_type.size();
+        }
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sketch_struct_body() {
+        let layout =
+            StructLayout::parse("uint8_t direction;\n int16_t revolutions;\n float newtons;")
+                .unwrap();
+        assert_eq!(layout.fields.len(), 3);
+        assert_eq!(layout.fields[1].name, "revolutions");
+        assert_eq!(layout.fields[2].field_type, FieldType::F32);
+        assert_eq!(layout.size(), 7);
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        assert!(StructLayout::parse("u8 a, long b").is_err());
+        assert!(StructLayout::parse("").is_err());
+    }
+
+    #[test]
+    fn decodes_packed_fields() {
+        let layout = StructLayout::parse("u8 dir, i16 revs, f32 newtons").unwrap();
+        let mut bytes = vec![1];
+        bytes.extend((-300i16).to_le_bytes());
+        bytes.extend(2.5f32.to_le_bytes());
+        assert_eq!(
+            layout.decode(&bytes),
+            vec![
+                ("dir", FieldValue::Unsigned(1)),
+                ("revs", FieldValue::Signed(-300)),
+                ("newtons", FieldValue::Float(2.5)),
+            ]
+        );
+        // A short packet only decodes the fields that fit
+        assert_eq!(layout.decode(&bytes[..2]).len(), 1);
+    }
+}
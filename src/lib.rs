@@ -1,6 +1,20 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
-mod app;
-mod arduino;
-mod data_window;
-mod error_message;
+pub mod app;
+pub mod arduino;
+pub mod capture;
+pub mod clock;
+pub mod connection;
+pub mod data_window;
+pub mod device;
+pub mod error_message;
+pub mod export;
+pub mod frame;
+pub mod import;
+pub mod layout;
+pub mod ports;
+pub mod replay;
+pub mod serial_settings;
+pub mod store;
+pub mod terminal;
+pub use app::TemplateApp;
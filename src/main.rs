@@ -1,25 +1,17 @@
 #![warn(clippy::all, rust_2018_idioms)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
-mod app;
-mod arduino;
-mod data_window;
-mod error_message;
-use std::sync::{Arc, Mutex};
+use arduino_communication_gui::{arduino, connection};
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> eframe::Result<()> {
-    use app::TemplateApp;
+    use arduino_communication_gui::TemplateApp;
     use tokio::sync::mpsc;
 
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
-    let (tx_gui, mut rx_arduino) = mpsc::channel::<arduino::ThreadMSG>(100);
-    let (tx_arduino, rx_gui) = mpsc::channel::<arduino::ThreadMSG>(100);
-
-    let arduino_handler = Arc::new(Mutex::new(arduino::Arduino::new()));
-
-    let data = Arc::new(Mutex::new(Vec::new()));
+    let (tx_gui, rx_arduino) = mpsc::channel::<arduino::ThreadMSG>(100);
+    let (tx_arduino, rx_gui) = mpsc::channel::<connection::DeviceMSG>(1024);
 
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
@@ -37,36 +29,14 @@ async fn main() -> eframe::Result<()> {
         "Arduino Communication",
         native_options,
         Box::new(|cc| {
-            let _frame = cc.egui_ctx.clone();
-            let arduino_thread_handler = arduino_handler.clone();
-            let _data_ard = data.clone();
-            tokio::spawn(async move {
-                loop {
-                    match rx_arduino.recv().await {
-                        Some(msg) => match msg {
-                            arduino::ThreadMSG::Start((port, baud)) => {
-                                arduino_thread_handler
-                                    .lock()
-                                    .unwrap()
-                                    .connect(port, baud as u32);
-                                arduino_thread_handler
-                                    .lock()
-                                    .unwrap()
-                                    .read_loop(&mut rx_arduino, tx_arduino.clone());
-                            }
-                            arduino::ThreadMSG::Data(..) => {}
-                            arduino::ThreadMSG::Disconnect() => {
-                                arduino_thread_handler.lock().unwrap().disconnect();
-                            }
-                            _ => panic!("Invalid message sent to arduino thread!"),
-                        },
-                        None => {
-                            panic!("Transmitter has been dropped!");
-                        }
-                    }
-                }
-            });
-            Box::new(TemplateApp::new(cc, rx_gui, tx_gui, arduino_handler, data))
+            let frame = cc.egui_ctx.clone();
+            let port_events =
+                arduino_communication_gui::ports::watch(move || frame.request_repaint());
+            let frame = cc.egui_ctx.clone();
+            let manager =
+                connection::ConnectionManager::new(tx_arduino, move || frame.request_repaint());
+            tokio::spawn(manager.run(rx_arduino));
+            Box::new(TemplateApp::new(cc, rx_gui, tx_gui, port_events))
         }),
     )
 }
@@ -0,0 +1,219 @@
+/*
+ *  Serial ports
+ *
+ *  Port names aren't stable, a board that is unplugged and plugged back in can come back as
+ *  /dev/ttyUSB1 instead of /dev/ttyUSB0 or as a different COM port. USB boards also report a
+ *  vendor & product ID and usually a serial number, which together identify the board itself.
+ *
+ *  Listing the ports is slow on some systems, so it's done by a background watcher that polls
+ *  and reports what changed rather than by the UI every frame.
+ */
+
+use std::{fmt::Display, time::Duration};
+
+use tokio::sync::mpsc;
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+/// How often the watcher lists the ports
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum PortEvent {
+    Added(SerialPortInfo),
+    Removed(SerialPortInfo),
+}
+
+/// Identifies a USB board no matter which port it is plugged into
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub struct PortIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>, // Cheap clones don't always have one
+}
+
+impl PortIdentity {
+    /// Only USB ports have an identity
+    pub fn of(info: &SerialPortInfo) -> Option<Self> {
+        match &info.port_type {
+            SerialPortType::UsbPort(usb) => Some(Self {
+                vid: usb.vid,
+                pid: usb.pid,
+                serial_number: usb.serial_number.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Also used as the key for saved aliases, e.g. "2341:0058 5A8F1C2E"
+impl Display for PortIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)?;
+        match &self.serial_number {
+            Some(serial_number) => write!(f, " {}", serial_number),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Everything the OS says about the port, e.g. "USB 2341:0058 Arduino LLC Nano (SN 5A8F1C2E)"
+pub fn describe(info: &SerialPortInfo) -> String {
+    match &info.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut text = format!("USB {:04x}:{:04x}", usb.vid, usb.pid);
+            for detail in [&usb.manufacturer, &usb.product].into_iter().flatten() {
+                text += &format!(" {}", detail);
+            }
+            if let Some(serial_number) = &usb.serial_number {
+                text += &format!(" (SN {})", serial_number);
+            }
+            text
+        }
+        SerialPortType::PciPort => "PCI".to_owned(),
+        SerialPortType::BluetoothPort => "Bluetooth".to_owned(),
+        SerialPortType::Unknown => "Unknown type".to_owned(),
+    }
+}
+
+/// Finds the port to reopen. The board's identity is preferred as it survives being renamed, but
+/// without a serial number two identical boards can't be told apart so the name is tried first.
+pub fn find_port(
+    ports: &[SerialPortInfo],
+    port_name: &str,
+    identity: Option<&PortIdentity>,
+) -> Option<String> {
+    let same_name = ports.iter().find(|p| p.port_name == port_name);
+    let Some(identity) = identity else {
+        return same_name.map(|p| p.port_name.clone());
+    };
+    let matches = |p: &&SerialPortInfo| PortIdentity::of(p).as_ref() == Some(identity);
+    if identity.serial_number.is_none() {
+        if let Some(port) = same_name.filter(matches) {
+            return Some(port.port_name.clone());
+        }
+    }
+    ports.iter().find(matches).map(|p| p.port_name.clone())
+}
+
+/// What changed between two listings of the ports
+pub fn diff(old: &[SerialPortInfo], new: &[SerialPortInfo]) -> Vec<PortEvent> {
+    let removed = old
+        .iter()
+        .filter(|p| !new.contains(p))
+        .map(|p| PortEvent::Removed(p.clone()));
+    let added = new
+        .iter()
+        .filter(|p| !old.contains(p))
+        .map(|p| PortEvent::Added(p.clone()));
+    removed.chain(added).collect()
+}
+
+/// Lists the ports in the background and sends what changed each time, the first batch is every
+/// port that was already there. Stops once the receiver is dropped. `on_change` is called after
+/// each batch so the UI can be woken up.
+pub fn watch(on_change: impl Fn() + Send + 'static) -> mpsc::Receiver<Vec<PortEvent>> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut known = Vec::new();
+        let mut first = true;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let listed = tokio::task::spawn_blocking(tokio_serial::available_ports).await;
+            let ports = match listed {
+                Ok(Ok(ports)) => ports,
+                Ok(Err(e)) => {
+                    eprintln!("Error finding serial ports: {}", e);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let events = diff(&known, &ports);
+            if events.is_empty() && !first {
+                continue;
+            }
+            if tx.send(events).await.is_err() {
+                return;
+            }
+            known = ports;
+            first = false;
+            on_change();
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_serial::UsbPortInfo;
+
+    fn usb_port(name: &str, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_owned(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x2341,
+                pid: 0x0058,
+                serial_number: serial_number.map(str::to_owned),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn describes_ports() {
+        let port = usb_port("/dev/ttyACM0", Some("A1"));
+        assert_eq!(describe(&port), "USB 2341:0058 (SN A1)");
+        assert_eq!(PortIdentity::of(&port).unwrap().to_string(), "2341:0058 A1");
+    }
+
+    #[test]
+    fn diffs_port_lists() {
+        let old = vec![
+            usb_port("/dev/ttyACM0", Some("A")),
+            usb_port("/dev/ttyACM1", Some("B")),
+        ];
+        let new = vec![
+            usb_port("/dev/ttyACM1", Some("B")),
+            usb_port("/dev/ttyACM2", Some("C")),
+        ];
+        let events = diff(&old, &new);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], PortEvent::Removed(p) if p.port_name == "/dev/ttyACM0"));
+        assert!(matches!(&events[1], PortEvent::Added(p) if p.port_name == "/dev/ttyACM2"));
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn finds_renamed_board() {
+        let ports = vec![
+            usb_port("/dev/ttyACM0", Some("B")),
+            usb_port("/dev/ttyACM1", Some("A")),
+        ];
+        let identity = PortIdentity::of(&usb_port("/dev/ttyACM0", Some("A")));
+        assert_eq!(
+            find_port(&ports, "/dev/ttyACM0", identity.as_ref()),
+            Some("/dev/ttyACM1".to_owned())
+        );
+        // Gone entirely
+        assert_eq!(
+            find_port(&ports[..1], "/dev/ttyACM0", identity.as_ref()),
+            None
+        );
+    }
+
+    #[test]
+    fn prefers_name_without_serial_number() {
+        let ports = vec![
+            usb_port("/dev/ttyUSB0", None),
+            usb_port("/dev/ttyUSB1", None),
+        ];
+        let identity = PortIdentity::of(&ports[1]);
+        assert_eq!(
+            find_port(&ports, "/dev/ttyUSB1", identity.as_ref()),
+            Some("/dev/ttyUSB1".to_owned())
+        );
+        assert_eq!(find_port(&ports, "/dev/ttyS0", None), None);
+    }
+}
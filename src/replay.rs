@@ -0,0 +1,454 @@
+/*
+ *  Replay
+ *
+ *  Plays a capture file back as if its devices were connected now, so the crane's data can be
+ *  looked at again without the crane. Samples are sent as ThreadMSG::Data and the bytes read
+ *  from the port as ThreadMSG::Raw, through the same path as live data & spaced out the way they
+ *  were recorded (scaled by the speed), so the windows & terminals can't tell the difference.
+ *
+ *  Samples are restamped with the time they are replayed, only the Arduino's own clock is kept
+ *  as it was. Otherwise the retention would throw replayed samples away as soon as they arrive,
+ *  and seeking backwards would send time backwards. Each recorded device becomes a device of its
+ *  own named "<device> (replay)", so replaying next to a live board doesn't mix the two.
+ */
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::arduino::{PacketData, ThreadMSG};
+use crate::capture::{read_capture, Capture, CaptureEvent, CaptureRecord};
+use crate::clock::SampleTime;
+use crate::connection::DeviceMSG;
+
+/// How often the GUI is told where playback has got to while playing
+const STATUS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sent by the GUI to control the replay
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayCommand {
+    Play,
+    Pause,
+    Step,           // Pauses & sends the next sample
+    Seek(Duration), // Time since the start of the capture
+    Speed(f64),     // 1.0 is real time
+    Loop(bool),     // Start again from the beginning after the last sample
+    Stop,
+}
+
+/// Where the replay has got to, sent to the GUI
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayStatus {
+    pub id: u64, // Which replay this is about, an old one can still be stopping
+    pub path: PathBuf,
+    pub position: Duration,
+    pub length: Duration,
+    pub playing: bool,
+    pub speed: f64,
+    pub looping: bool,
+    pub truncated: bool, // The capture's last record was damaged & left out
+    pub stopped: bool,   // The replay has ended & its task is gone
+}
+
+/// The name replayed data is shown under
+pub fn replay_device(device: &str) -> String {
+    format!("{} (replay)", device)
+}
+
+/// Reads the capture & plays it, obeying the commands until told to stop or the sender is dropped
+pub async fn replay(
+    path: PathBuf,
+    id: u64,
+    mut commands: mpsc::Receiver<ReplayCommand>,
+    events: mpsc::Sender<DeviceMSG>,
+) {
+    let read = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || read_capture(&path)).await
+    };
+    let capture = match read {
+        Ok(Ok(capture)) => capture,
+        Ok(Err(e)) => return failed(&events, id, &path, e).await,
+        Err(e) => return failed(&events, id, &path, e).await,
+    };
+    let mut player = Player::new(id, path, capture, events);
+    player.send_status().await;
+    let mut status = tokio::time::interval(STATUS_INTERVAL);
+    loop {
+        let due = player.next_due();
+        tokio::select! {
+            command = commands.recv() => match command {
+                None | Some(ReplayCommand::Stop) => break,
+                Some(command) => {
+                    player.command(command).await;
+                    player.send_status().await;
+                }
+            },
+            // The deadline is still worked out when there isn't one, it just isn't waited for
+            _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                player.play_due().await;
+            }
+            _ = status.tick(), if player.playing => player.send_status().await,
+        }
+    }
+    player.stopped = true;
+    player.send_status().await;
+}
+
+async fn failed(events: &mpsc::Sender<DeviceMSG>, id: u64, path: &Path, e: impl std::fmt::Display) {
+    let msg = ThreadMSG::ReplayFailed((id, format!("{}: {}", path.display(), e)));
+    let _ = events.send((String::new(), msg)).await;
+}
+
+struct Player {
+    id: u64,
+    path: PathBuf,
+    records: Vec<CaptureRecord>,
+    positions: Vec<Duration>, // When each record happens, from the start of the capture
+    truncated: bool,
+    events: mpsc::Sender<DeviceMSG>,
+    next: usize, // Record to send next
+    playing: bool,
+    speed: f64,
+    looping: bool,
+    started: (Instant, Duration), // When playing last started & the position it started from
+    paused_at: Duration,          // The position while paused
+    channels: BTreeSet<(String, u8)>, // Channels sent so far, to mark gaps in
+    stopped: bool,
+}
+
+impl Player {
+    fn new(id: u64, path: PathBuf, capture: Capture, events: mpsc::Sender<DeviceMSG>) -> Self {
+        let records = capture.records;
+        let first = records
+            .first()
+            .map(|r| r.time.wall)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        // The wall clock can be stepped back while recording, never let time go backwards
+        let mut last = Duration::ZERO;
+        let positions = records
+            .iter()
+            .map(|r| {
+                last = last.max(r.time.wall.duration_since(first).unwrap_or_default());
+                last
+            })
+            .collect();
+        Self {
+            id,
+            path,
+            playing: !records.is_empty(),
+            records,
+            positions,
+            truncated: capture.truncated,
+            events,
+            next: 0,
+            speed: 1.0,
+            looping: false,
+            started: (Instant::now(), Duration::ZERO),
+            paused_at: Duration::ZERO,
+            channels: BTreeSet::new(),
+            stopped: false,
+        }
+    }
+
+    fn length(&self) -> Duration {
+        self.positions.last().copied().unwrap_or_default()
+    }
+
+    fn position(&self) -> Duration {
+        match self.playing {
+            true => {
+                let (at, from) = self.started;
+                (from + at.elapsed().mul_f64(self.speed)).min(self.length())
+            }
+            false => self.paused_at,
+        }
+    }
+
+    /// When the next record should be sent, None while paused
+    fn next_due(&self) -> Option<Instant> {
+        if !self.playing {
+            return None;
+        }
+        let (at, from) = self.started;
+        let position = self.positions.get(self.next)?;
+        Some(at + position.saturating_sub(from).div_f64(self.speed))
+    }
+
+    /// Carries on from `position`, the clock is restarted whenever playing, seeking or the speed
+    /// changes
+    fn restart_from(&mut self, position: Duration) {
+        self.paused_at = position;
+        self.started = (Instant::now(), position);
+    }
+
+    async fn command(&mut self, command: ReplayCommand) {
+        match command {
+            ReplayCommand::Play if !self.playing => {
+                if self.next >= self.records.len() {
+                    self.seek(Duration::ZERO).await;
+                }
+                self.restart_from(self.paused_at);
+                self.playing = true;
+            }
+            ReplayCommand::Pause if self.playing => {
+                self.paused_at = self.position();
+                self.playing = false;
+            }
+            ReplayCommand::Step => {
+                self.playing = false;
+                // Raw bytes are read before the samples decoded from them, send them together
+                while let Some(position) = self.positions.get(self.next).copied() {
+                    let sample = matches!(self.records[self.next].event, CaptureEvent::Data(_));
+                    self.send(self.next).await;
+                    self.next += 1;
+                    self.paused_at = position;
+                    if sample {
+                        break;
+                    }
+                }
+            }
+            ReplayCommand::Seek(position) => self.seek(position).await,
+            ReplayCommand::Speed(speed) if speed > 0.0 => {
+                self.restart_from(self.position());
+                self.speed = speed;
+            }
+            ReplayCommand::Loop(looping) => self.looping = looping,
+            _ => (),
+        }
+    }
+
+    /// Jumps to the position, the device & channel descriptions from before it are sent again so
+    /// windows exist for everything even when the start was skipped
+    async fn seek(&mut self, position: Duration) {
+        let position = position.min(self.length());
+        self.next = self.positions.partition_point(|p| *p < position);
+        for i in 0..self.next {
+            if let CaptureEvent::Data(
+                PacketData::Descriptor(_) | PacketData::Channel(_) | PacketData::Metadata(_),
+            ) = self.records[i].event
+            {
+                self.send(i).await;
+            }
+        }
+        self.mark_gaps(None).await;
+        self.restart_from(position);
+    }
+
+    /// Sends everything that is due, then loops or pauses at the end
+    async fn play_due(&mut self) {
+        let position = self.position();
+        while self.next < self.records.len() && self.positions[self.next] <= position {
+            self.send(self.next).await;
+            self.next += 1;
+        }
+        if self.next >= self.records.len() {
+            match self.looping {
+                true => self.seek(Duration::ZERO).await,
+                false => {
+                    self.paused_at = self.length();
+                    self.playing = false;
+                    self.send_status().await;
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, index: usize) {
+        let record = &self.records[index];
+        let device = replay_device(&record.device);
+        match &record.event {
+            CaptureEvent::Data(data) => {
+                let mut data = data.clone();
+                if let Some(time) = data.time_mut() {
+                    *time = SampleTime {
+                        device: time.device,
+                        ..SampleTime::now()
+                    };
+                }
+                if let Some(id) = data.id() {
+                    self.channels.insert((device.clone(), id));
+                }
+                let _ = self.events.send((device, ThreadMSG::Data(data))).await;
+            }
+            CaptureEvent::Lost => self.mark_gaps(Some(&device)).await,
+            CaptureEvent::Raw(bytes) => {
+                let time = SampleTime {
+                    device: record.time.device,
+                    ..SampleTime::now()
+                };
+                let msg = ThreadMSG::Raw((bytes.clone(), time));
+                let _ = self.events.send((device, msg)).await;
+            }
+        }
+    }
+
+    /// Breaks the lines of the device's channels, or every channel, so nothing is drawn across
+    /// a jump in playback or where the connection was lost while recording
+    async fn mark_gaps(&self, device: Option<&str>) {
+        let time = SampleTime::now();
+        let channels = self
+            .channels
+            .iter()
+            .filter(|(d, _)| device.map_or(true, |device| d == device));
+        for (device, id) in channels {
+            let msg = ThreadMSG::Data(PacketData::Gap(*id, time));
+            let _ = self.events.send((device.clone(), msg)).await;
+        }
+    }
+
+    async fn send_status(&self) {
+        let status = ReplayStatus {
+            id: self.id,
+            path: self.path.clone(),
+            position: self.position(),
+            length: self.length(),
+            playing: self.playing,
+            speed: self.speed,
+            looping: self.looping,
+            truncated: self.truncated,
+            stopped: self.stopped,
+        };
+        let _ = self
+            .events
+            .send((String::new(), ThreadMSG::ReplayStatus(status)))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureWriter;
+
+    fn record(device: &str, millis: u64, value: f64) -> CaptureRecord {
+        let time = SampleTime {
+            wall: SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_000 + millis),
+            device: Some(Duration::from_millis(millis)),
+            ..SampleTime::now()
+        };
+        CaptureRecord {
+            device: device.to_owned(),
+            time,
+            event: CaptureEvent::Data(PacketData::Float(value, 1, time)),
+        }
+    }
+
+    fn write_capture(name: &str, records: &[CaptureRecord]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("acg-replay-{}-{}.acap", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = CaptureWriter::create(&path).unwrap();
+        for r in records {
+            writer.write(&r.device, &r.time, &r.event).unwrap();
+        }
+        writer.flush().unwrap();
+        path
+    }
+
+    /// The values sent as data, in order, from when the replay says it's playing until it has
+    /// paused at the end
+    async fn played_values(events: &mut mpsc::Receiver<DeviceMSG>) -> Vec<(String, f64)> {
+        let mut values = Vec::new();
+        let mut playing = false;
+        while let Some((device, msg)) = events.recv().await {
+            match msg {
+                ThreadMSG::Data(PacketData::Float(value, _, _)) => values.push((device, value)),
+                ThreadMSG::ReplayStatus(status) if status.playing => playing = true,
+                ThreadMSG::ReplayStatus(_) if playing => break,
+                _ => (),
+            }
+        }
+        values
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_in_order_at_any_speed() {
+        let path = write_capture(
+            "order",
+            &[
+                record("a", 0, 1.0),
+                record("b", 500, 2.0),
+                record("a", 2_000, 3.0),
+            ],
+        );
+        let (commands, commands_rx) = mpsc::channel(8);
+        let (events, mut events_rx) = mpsc::channel(64);
+        let task = tokio::spawn(replay(path.clone(), 1, commands_rx, events));
+        commands.send(ReplayCommand::Speed(10.0)).await.unwrap();
+        let start = Instant::now();
+        let values = played_values(&mut events_rx).await;
+        assert_eq!(
+            values,
+            vec![
+                ("a (replay)".to_owned(), 1.0),
+                ("b (replay)".to_owned(), 2.0),
+                ("a (replay)".to_owned(), 3.0)
+            ]
+        );
+        // Two seconds of capture at ten times speed, the clock only moves when nothing else can
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+
+        // Seeking back & playing again replays from there
+        commands
+            .send(ReplayCommand::Seek(Duration::from_millis(400)))
+            .await
+            .unwrap();
+        commands.send(ReplayCommand::Play).await.unwrap();
+        let values = played_values(&mut events_rx).await;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].1, 2.0);
+
+        drop(commands);
+        task.await.unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn steps_one_sample_at_a_time() {
+        let mut raw = record("a", 59_990, 0.0);
+        raw.event = CaptureEvent::Raw(b"Load: 2\r\n".to_vec());
+        let path = write_capture(
+            "step",
+            &[record("a", 0, 1.0), raw, record("a", 60_000, 2.0)],
+        );
+        let (commands, commands_rx) = mpsc::channel(8);
+        let (events, mut events_rx) = mpsc::channel(64);
+        let task = tokio::spawn(replay(path.clone(), 1, commands_rx, events));
+        commands.send(ReplayCommand::Pause).await.unwrap();
+        commands.send(ReplayCommand::Step).await.unwrap();
+        commands.send(ReplayCommand::Step).await.unwrap();
+        commands.send(ReplayCommand::Stop).await.unwrap();
+        let mut sent = Vec::new();
+        let mut last = None;
+        while let Some((device, msg)) = events_rx.recv().await {
+            match msg {
+                ThreadMSG::Data(PacketData::Float(value, _, _)) => {
+                    sent.push(format!("{} {}", device, value))
+                }
+                ThreadMSG::Raw((bytes, _)) => sent.push(format!(
+                    "{} {}",
+                    device,
+                    String::from_utf8_lossy(&bytes).trim()
+                )),
+                ThreadMSG::ReplayStatus(status) => last = Some(status),
+                _ => (),
+            }
+        }
+        task.await.unwrap();
+        let last = last.unwrap();
+        // The raw bytes go to the terminal along with the sample after them
+        assert_eq!(
+            sent,
+            vec!["a (replay) 1", "a (replay) Load: 2", "a (replay) 2"]
+        );
+        assert!(last.stopped && !last.playing);
+        assert_eq!(last.position, Duration::from_secs(60));
+        let _ = std::fs::remove_file(path);
+    }
+}
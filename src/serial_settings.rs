@@ -0,0 +1,231 @@
+/*
+ *  Serial line settings
+ *
+ *  Everything needed to open a port, picked in the connection dialog and remembered per port.
+ *  The enums mirror tokio_serial's so they can be saved with the rest of the app state.
+ */
+
+use std::fmt::Display;
+
+/// Baud rates offered in the dialog, anything else can be typed in as a custom rate
+pub const BAUD_PRESETS: [u32; 14] = [
+    300, 1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 250000, 500000, 1000000,
+    2000000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FlowControl {
+    None,
+    Software, // XON/XOFF
+    Hardware, // RTS/CTS
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub dtr: bool,        // Most Arduinos reset when DTR is raised
+    pub rts: bool,        // Ignored with hardware flow control
+    pub text_lines: bool, // Split printed text off into a log channel, see PacketDecoder
+}
+
+/// 9600 8N1, what the sketches use
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            dtr: true,
+            rts: true,
+            text_lines: false,
+        }
+    }
+}
+
+impl From<DataBits> for tokio_serial::DataBits {
+    fn from(bits: DataBits) -> Self {
+        match bits {
+            DataBits::Five => Self::Five,
+            DataBits::Six => Self::Six,
+            DataBits::Seven => Self::Seven,
+            DataBits::Eight => Self::Eight,
+        }
+    }
+}
+
+impl From<Parity> for tokio_serial::Parity {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => Self::None,
+            Parity::Odd => Self::Odd,
+            Parity::Even => Self::Even,
+        }
+    }
+}
+
+impl From<StopBits> for tokio_serial::StopBits {
+    fn from(bits: StopBits) -> Self {
+        match bits {
+            StopBits::One => Self::One,
+            StopBits::Two => Self::Two,
+        }
+    }
+}
+
+impl From<FlowControl> for tokio_serial::FlowControl {
+    fn from(flow: FlowControl) -> Self {
+        match flow {
+            FlowControl::None => Self::None,
+            FlowControl::Software => Self::Software,
+            FlowControl::Hardware => Self::Hardware,
+        }
+    }
+}
+
+/// Short form such as "9600 8N1"
+impl Display for SerialSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, data_bits, parity, stop_bits)
+    }
+}
+
+impl SerialSettings {
+    /// Builds the port with these settings, DTR & RTS are set once it is open
+    pub fn builder(&self, port_path: &str) -> tokio_serial::SerialPortBuilder {
+        tokio_serial::new(port_path, self.baud_rate)
+            .data_bits(self.data_bits.into())
+            .parity(self.parity.into())
+            .stop_bits(self.stop_bits.into())
+            .flow_control(self.flow_control.into())
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("serial_settings")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Baud rate:");
+                ui.horizontal(|ui| {
+                    let custom = !BAUD_PRESETS.contains(&self.baud_rate);
+                    egui::ComboBox::from_id_source("baud_rate")
+                        .selected_text(match custom {
+                            true => "Custom".to_owned(),
+                            false => self.baud_rate.to_string(),
+                        })
+                        .show_ui(ui, |ui| {
+                            for baud in BAUD_PRESETS {
+                                ui.selectable_value(&mut self.baud_rate, baud, baud.to_string());
+                            }
+                        });
+                    ui.add(egui::DragValue::new(&mut self.baud_rate).clamp_range(1..=u32::MAX));
+                });
+                ui.end_row();
+
+                ui.label("Data bits:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.data_bits, DataBits::Five, "5");
+                    ui.radio_value(&mut self.data_bits, DataBits::Six, "6");
+                    ui.radio_value(&mut self.data_bits, DataBits::Seven, "7");
+                    ui.radio_value(&mut self.data_bits, DataBits::Eight, "8");
+                });
+                ui.end_row();
+
+                ui.label("Parity:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.parity, Parity::None, "None");
+                    ui.radio_value(&mut self.parity, Parity::Odd, "Odd");
+                    ui.radio_value(&mut self.parity, Parity::Even, "Even");
+                });
+                ui.end_row();
+
+                ui.label("Stop bits:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.stop_bits, StopBits::One, "1");
+                    ui.radio_value(&mut self.stop_bits, StopBits::Two, "2");
+                });
+                ui.end_row();
+
+                ui.label("Flow control:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.flow_control, FlowControl::None, "None");
+                    ui.radio_value(&mut self.flow_control, FlowControl::Software, "XON/XOFF");
+                    ui.radio_value(&mut self.flow_control, FlowControl::Hardware, "RTS/CTS");
+                });
+                ui.end_row();
+
+                ui.label("Lines:");
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.dtr, "DTR");
+                    ui.add_enabled(
+                        self.flow_control != FlowControl::Hardware,
+                        egui::Checkbox::new(&mut self.rts, "RTS"),
+                    );
+                });
+                ui.end_row();
+
+                ui.label("Text:");
+                ui.checkbox(&mut self.text_lines, "Log printed lines separately");
+                ui.end_row();
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_settings() {
+        assert_eq!(SerialSettings::default().to_string(), "9600 8N1");
+        let settings = SerialSettings {
+            baud_rate: 115200,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            ..Default::default()
+        };
+        assert_eq!(settings.to_string(), "115200 7E2");
+    }
+}
@@ -0,0 +1,439 @@
+/*
+ *  Channel storage
+ *
+ *  Each channel keeps its recent samples in a ring buffer, trimmed by sample count and/or age so
+ *  a long run doesn't use up all the memory. Numeric samples that are trimmed can be kept as a
+ *  downsampled history, each bucket holds the min, max & mean of every field over a fixed period.
+ *
+ *  Channels are kept in a map keyed by device & packet ID, so a stray ID only adds one channel.
+ *  Each channel remembers the type of data it holds, firmware shouldn't change the type sent
+ *  under an ID but a corrupt packet or a firmware update can.
+ */
+
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+use crate::arduino::PacketData;
+use crate::clock::SampleTime;
+
+/// Device name & packet ID
+pub type ChannelKey = (String, u8);
+
+/// How much of each channel is kept
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Retention {
+    pub max_samples: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub history: bool,      // Keep trimmed samples as min/max/mean buckets
+    pub bucket: Duration,   // Time covered by each history bucket
+    pub max_buckets: usize, // Oldest buckets are dropped past this
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_samples: Some(100_000),
+            max_age: None,
+            history: true,
+            bucket: Duration::from_secs(1),
+            max_buckets: 86_400, // A day of one second buckets
+        }
+    }
+}
+
+impl Retention {
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("retention").num_columns(2).show(ui, |ui| {
+            let mut limit_samples = self.max_samples.is_some();
+            ui.checkbox(&mut limit_samples, "Keep at most");
+            let mut max_samples = self.max_samples.unwrap_or(100_000);
+            ui.add_enabled(
+                limit_samples,
+                egui::DragValue::new(&mut max_samples)
+                    .clamp_range(1..=usize::MAX)
+                    .suffix(" samples"),
+            );
+            self.max_samples = limit_samples.then_some(max_samples);
+            ui.end_row();
+
+            let mut limit_age = self.max_age.is_some();
+            ui.checkbox(&mut limit_age, "Keep samples for");
+            let mut max_age = self.max_age.unwrap_or(Duration::from_secs(600)).as_secs();
+            ui.add_enabled(
+                limit_age,
+                egui::DragValue::new(&mut max_age)
+                    .clamp_range(1..=u64::MAX)
+                    .suffix(" s"),
+            );
+            self.max_age = limit_age.then_some(Duration::from_secs(max_age));
+            ui.end_row();
+
+            ui.checkbox(&mut self.history, "Keep history every");
+            let mut bucket = self.bucket.as_secs_f64();
+            ui.add_enabled(
+                self.history,
+                egui::DragValue::new(&mut bucket)
+                    .speed(0.1)
+                    .clamp_range(0.01..=3600.0)
+                    .suffix(" s"),
+            );
+            self.bucket = Duration::from_secs_f64(bucket);
+            ui.end_row();
+
+            ui.label("History length:");
+            ui.add_enabled(
+                self.history,
+                egui::DragValue::new(&mut self.max_buckets)
+                    .clamp_range(1..=usize::MAX)
+                    .suffix(" buckets"),
+            );
+            ui.end_row();
+        });
+    }
+}
+
+/// Summary of the samples in one period of the history
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub start: SampleTime, // Time of the first sample
+    pub end: SampleTime,   // Time of the last sample
+    pub min: Vec<f64>,     // One for each field
+    pub max: Vec<f64>,
+    sum: Vec<f64>,
+    pub count: usize,
+    pub after_gap: bool, // The connection was lost between this bucket & the one before
+}
+
+impl Bucket {
+    fn new(time: SampleTime, values: Vec<f64>, after_gap: bool) -> Self {
+        Self {
+            start: time,
+            end: time,
+            min: values.clone(),
+            max: values.clone(),
+            sum: values,
+            count: 1,
+            after_gap,
+        }
+    }
+
+    fn add(&mut self, time: SampleTime, values: &[f64]) {
+        // Vectors can gain fields part way through, missing fields just have fewer samples
+        for (i, value) in values.iter().enumerate() {
+            match self.sum.get_mut(i) {
+                Some(sum) => {
+                    *sum += value;
+                    self.min[i] = self.min[i].min(*value);
+                    self.max[i] = self.max[i].max(*value);
+                }
+                None => {
+                    self.sum.push(*value);
+                    self.min.push(*value);
+                    self.max.push(*value);
+                }
+            }
+        }
+        self.end = time;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> Vec<f64> {
+        self.sum.iter().map(|sum| sum / self.count as f64).collect()
+    }
+
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.sum.len() * 3 * size_of::<f64>()
+    }
+}
+
+/// A channel's data changed type, e.g. from Float to String
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeChange {
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+/// One channel's samples, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct ChannelStore {
+    samples: VecDeque<PacketData>,
+    history: VecDeque<Bucket>,
+    sample_bytes: usize, // Kept up to date as samples come & go
+    history_bytes: usize,
+    split: bool, // The next bucket can't be merged with the last, there was a gap between them
+    kind: Option<&'static str>, // Type of the last sample, see PacketData::display_variant
+    fixed: bool, // Imported, never trimmed
+}
+
+impl ChannelStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A channel that was imported rather than received, everything is kept whatever the
+    /// retention is
+    pub fn imported(samples: VecDeque<PacketData>) -> Self {
+        Self {
+            sample_bytes: samples.iter().map(sample_size).sum(),
+            kind: samples.back().map(PacketData::display_variant),
+            samples,
+            fixed: true,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the sample and trims the channel, returns the change if the sample's type is
+    /// different from the last one's
+    pub fn push(&mut self, data: PacketData, retention: &Retention) -> Option<TypeChange> {
+        let mut change = None;
+        if data.time().is_some() && !matches!(data, PacketData::Gap(_, _)) {
+            let kind = data.display_variant();
+            change = match self.kind.replace(kind) {
+                Some(from) if from != kind => Some(TypeChange { from, to: kind }),
+                _ => None,
+            };
+        }
+        self.sample_bytes += sample_size(&data);
+        self.samples.push_back(data);
+        self.trim(retention, Instant::now());
+        change
+    }
+
+    /// Drops samples outside the retention, moving them into the history if it's kept
+    pub fn trim(&mut self, retention: &Retention, now: Instant) {
+        if self.fixed {
+            return;
+        }
+        loop {
+            let too_many = retention
+                .max_samples
+                .is_some_and(|max| self.samples.len() > max);
+            let too_old = match (retention.max_age, self.samples.front()) {
+                (Some(max_age), Some(oldest)) => oldest
+                    .time()
+                    .is_some_and(|t| now.saturating_duration_since(t.host) > max_age),
+                _ => false,
+            };
+            if !too_many && !too_old {
+                break;
+            }
+            let Some(oldest) = self.samples.pop_front() else {
+                break;
+            };
+            self.sample_bytes -= sample_size(&oldest);
+            if retention.history {
+                self.add_to_history(&oldest, retention);
+            }
+        }
+        while self.history.len() > retention.max_buckets {
+            if let Some(bucket) = self.history.pop_front() {
+                self.history_bytes -= bucket.memory_usage();
+            }
+        }
+    }
+
+    /// How long until the oldest sample is too old to keep, None if nothing is going to expire
+    pub fn next_expiry(&self, retention: &Retention, now: Instant) -> Option<Duration> {
+        if self.fixed {
+            return None;
+        }
+        let max_age = retention.max_age?;
+        let oldest = self.samples.front()?.time()?;
+        // Samples are dropped once they're older than the max age, not as soon as they reach it
+        let age = now.saturating_duration_since(oldest.host);
+        Some(max_age.saturating_sub(age) + Duration::from_millis(1))
+    }
+
+    fn add_to_history(&mut self, data: &PacketData, retention: &Retention) {
+        if let PacketData::Gap(_, _) = data {
+            self.split = true;
+            return;
+        }
+        let (Some(values), Some(time)) = (data.values(), data.time()) else {
+            return;
+        };
+        match self.history.back_mut() {
+            Some(bucket)
+                if !self.split
+                    && time.host.saturating_duration_since(bucket.start.host)
+                        < retention.bucket =>
+            {
+                self.history_bytes -= bucket.memory_usage();
+                bucket.add(*time, &values);
+                self.history_bytes += bucket.memory_usage();
+            }
+            _ => {
+                let bucket = Bucket::new(*time, values, self.split);
+                self.history_bytes += bucket.memory_usage();
+                self.history.push_back(bucket);
+                self.split = false;
+            }
+        }
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.fixed
+    }
+
+    /// Type of the data the channel holds, None until something arrives
+    pub fn kind(&self) -> Option<&'static str> {
+        self.kind
+    }
+
+    pub fn samples(&self) -> &VecDeque<PacketData> {
+        &self.samples
+    }
+
+    pub fn history(&self) -> &VecDeque<Bucket> {
+        &self.history
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Roughly how many bytes the channel is using
+    pub fn memory_usage(&self) -> usize {
+        self.sample_bytes + self.history_bytes
+    }
+}
+
+/// Roughly how many bytes a sample uses, including what it points to
+pub fn sample_size(data: &PacketData) -> usize {
+    size_of::<PacketData>()
+        + match data {
+            PacketData::String(s, _, _) => s.capacity(),
+            PacketData::Binary(b, _, _) => b.capacity(),
+            PacketData::Vector(v, _, _) => v.capacity() * size_of::<crate::layout::FieldValue>(),
+            _ => 0,
+        }
+}
+
+/// Formats a byte count such as "1.5 MiB"
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, millis: u64) -> SampleTime {
+        SampleTime {
+            host: start + Duration::from_millis(millis),
+            ..SampleTime::now()
+        }
+    }
+
+    #[test]
+    fn trims_by_count_into_history() {
+        let retention = Retention {
+            max_samples: Some(3),
+            bucket: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut store = ChannelStore::new();
+        for (i, value) in [1.0, 5.0, 3.0, 10.0, 2.0, 4.0].into_iter().enumerate() {
+            store.push(
+                PacketData::Float(value, 0, at(start, i as u64 * 40)),
+                &retention,
+            );
+        }
+        assert_eq!(store.len(), 3);
+        // 0, 40 & 80 ms are in the first bucket
+        assert_eq!(store.history().len(), 1);
+        let bucket = &store.history()[0];
+        assert_eq!((bucket.min[0], bucket.max[0], bucket.count), (1.0, 5.0, 3));
+        assert_eq!(bucket.mean(), vec![3.0]);
+        assert_eq!(
+            store.memory_usage(),
+            3 * size_of::<PacketData>() + bucket.memory_usage()
+        );
+    }
+
+    #[test]
+    fn trims_by_age_and_splits_at_gaps() {
+        let retention = Retention {
+            max_samples: None,
+            max_age: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut store = ChannelStore::new();
+        store.push(PacketData::Integer(1, 0, at(start, 0)), &retention);
+        store.push(PacketData::Gap(0, at(start, 100)), &retention);
+        store.push(PacketData::Integer(2, 0, at(start, 200)), &retention);
+        store.push(
+            PacketData::String("x".to_owned(), 0, at(start, 300)),
+            &retention,
+        );
+        assert_eq!(
+            store.next_expiry(&retention, start + Duration::from_millis(600)),
+            Some(Duration::from_millis(401))
+        );
+        store.trim(&retention, start + Duration::from_millis(1250));
+        assert_eq!(store.len(), 1, "Only the string is under a second old");
+        // Both integers fit in one bucket, but the gap keeps them apart
+        assert_eq!(store.history().len(), 2);
+        assert!(store.history()[1].after_gap);
+        store.trim(&retention, start + Duration::from_secs(5));
+        assert!(store.is_empty());
+        assert_eq!(store.next_expiry(&retention, start), None);
+        assert_eq!(
+            store.history().len(),
+            2,
+            "Strings aren't kept in the history"
+        );
+    }
+
+    #[test]
+    fn tracks_type_changes() {
+        let retention = Retention::default();
+        let mut store = ChannelStore::new();
+        let time = SampleTime::now();
+        assert_eq!(store.kind(), None);
+        assert_eq!(
+            store.push(PacketData::Float(1.0, 7, time), &retention),
+            None
+        );
+        assert_eq!(store.push(PacketData::Gap(7, time), &retention), None);
+        assert_eq!(
+            store.push(PacketData::Float(2.0, 7, time), &retention),
+            None
+        );
+        assert_eq!(
+            store.push(PacketData::String("?".to_owned(), 7, time), &retention),
+            Some(TypeChange {
+                from: "Float",
+                to: "String"
+            })
+        );
+        assert_eq!(store.kind(), Some("String"));
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+}
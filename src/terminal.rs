@@ -0,0 +1,182 @@
+/*
+ *  Serial terminal
+ *
+ *  Shows the bytes read from a device exactly as they arrived, before any decoding, as text or
+ *  as a hex dump. Useful for seeing what a sketch prints or why its frames aren't decoding.
+ *  Each device keeps the most recent bytes whether or not its terminal is open.
+ */
+
+use std::collections::VecDeque;
+
+use egui::ScrollArea;
+
+use crate::clock::{format_utc, unix_secs, SampleTime};
+
+/// Bytes kept for each device, the oldest reads are dropped past this
+pub const TERMINAL_CAPACITY: usize = 64 * 1024;
+
+/// Bytes on each row of the hex dump
+const HEX_ROW_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminalView {
+    Text, // Printable text, anything else is shown as \xNN
+    Hex,  // Hex dump of each read
+}
+
+#[derive(Debug)]
+pub struct Terminal {
+    pub device: String,
+    pub open: bool,
+    reads: VecDeque<(SampleTime, Vec<u8>)>,
+    bytes: usize, // Total length of the reads
+    view: TerminalView,
+    paused: bool, // Stops adding reads so the output can be looked at
+}
+
+impl Terminal {
+    pub fn new(device: String) -> Self {
+        Self {
+            device,
+            open: false,
+            reads: VecDeque::new(),
+            bytes: 0,
+            view: TerminalView::Text,
+            paused: false,
+        }
+    }
+
+    /// Adds bytes read from the port, unless paused
+    pub fn push(&mut self, time: SampleTime, bytes: Vec<u8>) {
+        if self.paused {
+            return;
+        }
+        self.bytes += bytes.len();
+        self.reads.push_back((time, bytes));
+        while self.bytes > TERMINAL_CAPACITY {
+            match self.reads.pop_front() {
+                Some((_, read)) => self.bytes -= read.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) {
+        let mut open = self.open;
+        egui::Window::new(format!("Terminal: {}", self.device))
+            .id(egui::Id::new(("terminal", &self.device)))
+            .resizable(true)
+            .open(&mut open)
+            .show(ctx, |ui| self.ui(ui));
+        self.open = open;
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.view, TerminalView::Text, "ASCII");
+            ui.radio_value(&mut self.view, TerminalView::Hex, "Hex");
+            ui.separator();
+            ui.checkbox(&mut self.paused, "Pause");
+            if ui.button("Clear").clicked() {
+                self.reads.clear();
+                self.bytes = 0;
+            }
+            ui.label(format!("{} bytes", self.bytes));
+        });
+        ui.separator();
+        let text = match self.view {
+            TerminalView::Text => format_text(&self.reads),
+            TerminalView::Hex => format_hex(&self.reads),
+        };
+        ScrollArea::both()
+            .auto_shrink(false)
+            .stick_to_bottom(true)
+            .show(ui, |ui| ui.monospace(text));
+    }
+}
+
+/// Time of day the read arrived, "HH:MM:SS.mmm" in UTC
+fn format_time(time: &SampleTime) -> String {
+    format_utc(unix_secs(time.wall))[11..].to_owned()
+}
+
+/// The reads joined back into text, each line starting with the time its first byte arrived
+fn format_text(reads: &VecDeque<(SampleTime, Vec<u8>)>) -> String {
+    let mut text = String::new();
+    let mut line_start = true;
+    for (time, read) in reads {
+        for byte in read {
+            if line_start {
+                text.push_str(&format!("[{}] ", format_time(time)));
+                line_start = false;
+            }
+            match byte {
+                b'\n' => {
+                    text.push('\n');
+                    line_start = true;
+                }
+                b'\r' => (),
+                b'\t' | 0x20..=0x7E => text.push(*byte as char),
+                _ => text.push_str(&format!("\\x{:02X}", byte)),
+            }
+        }
+    }
+    text
+}
+
+/// Every read as rows of offset, hex & the printable characters, under the time it arrived
+fn format_hex(reads: &VecDeque<(SampleTime, Vec<u8>)>) -> String {
+    let mut text = String::new();
+    for (time, read) in reads {
+        text.push_str(&format!("[{}] {} bytes\n", format_time(time), read.len()));
+        for (row, bytes) in read.chunks(HEX_ROW_LEN).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|b| match b {
+                    0x20..=0x7E => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            text.push_str(&format!(
+                "{:04X}  {:<width$}  |{}|\n",
+                row * HEX_ROW_LEN,
+                hex.join(" "),
+                ascii,
+                width = HEX_ROW_LEN * 3 - 1
+            ));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_text_and_hex() {
+        let reads = VecDeque::from([
+            (SampleTime::at_wall(1.0), b"BLE sc".to_vec()),
+            (SampleTime::at_wall(1.25), b"an\r\n\xA5Z".to_vec()),
+        ]);
+        assert_eq!(
+            format_text(&reads),
+            "[00:00:01.000] BLE scan\n[00:00:01.250] \\xA5Z"
+        );
+        assert_eq!(
+            format_hex(&reads).lines().nth(3),
+            Some("0000  61 6E 0D 0A A5 5A                                |an...Z|")
+        );
+    }
+
+    #[test]
+    fn keeps_the_newest_bytes() {
+        let mut terminal = Terminal::new("COM3".to_owned());
+        for _ in 0..3 {
+            terminal.push(SampleTime::at_wall(0.0), vec![0; TERMINAL_CAPACITY / 2]);
+        }
+        assert_eq!(terminal.bytes, TERMINAL_CAPACITY);
+        assert_eq!(terminal.reads.len(), 2);
+    }
+}
@@ -3,69 +3,113 @@
 bool temp_bool = true;
 int temp_count = 0;
 
+// Frame layout, must match src/frame.rs in the GUI
+// | 0xA5 | 0x5A | version | kind | id | len | payload ... | crc16 (LE) |
+#define FRAME_SYNC_1 0xA5
+#define FRAME_SYNC_2 0x5A
+#define PROTOCOL_VERSION 1
+#define FRAME_HEADER_LEN 6
+#define MAX_PAYLOAD_LEN 29
+#define FLAG_MICROS 0x40 // Set in the kind when the payload starts with micros()
+
 struct Packet {
     uint8_t PacketKind; // What type of packet it is
     uint8_t PacketId;
-    int8_t RawData[32];     // Raw bytes of data, 2 bytes taken for first two args, 
-                            // one byte for end of text, leaving 29 bytes for data per communication
-    
+    uint8_t Length;     // Number of bytes used in RawData
+    uint8_t RawData[MAX_PAYLOAD_LEN];
   };
 
 class PacketHandler {
   private:
+
+  // CRC-16/CCITT-FALSE
+  uint16_t crc16(uint8_t* data, uint8_t length) {
+    uint16_t crc = 0xFFFF;
+    for (int i = 0; i < length; i++) {
+      crc ^= (uint16_t) data[i] << 8;
+      for (int bit = 0; bit < 8; bit++) {
+        if (crc & 0x8000) {
+          crc = (crc << 1) ^ 0x1021;
+        } else {
+          crc <<= 1;
+        }
+      }
+    }
+    return crc;
+  }
     
   public: 
+
+  // Adds micros() to every packet so the GUI knows when the values were taken, rather than
+  // when they arrived
+  bool send_timestamps = false;
+
+  uint8_t hello_matched = 0; // How much of a hello frame has been received
   
   /* ID types: 
     1: String 
-    2: PosInteger
-    3: NegInteger
+    2: PosInteger (no longer sent)
+    3: NegInteger (no longer sent)
     4: Binary
+    5: Float (as text, no longer sent)
+    6: Float (f32)
+    7: Float (f64)
+    8 - 15: u8, i8, u16, i16, u32, i32, u64, i64
+    16: Vector
+    17: Device descriptor
+    18: Channel descriptor
+    19: Channel metadata
   */
   void send_packet(Packet* packet) {
-    uint8_t data_to_send[32] = {0};
-    data_to_send[0] = packet->PacketKind;
-    data_to_send[1] = packet->PacketId;
-    for (int i=2; i < 32; i++) {
-      data_to_send[i] = packet->RawData[i - 2];
-      if (packet->RawData[i-2] == 0x17) {
-        break;
-      }
+    uint8_t data_to_send[FRAME_HEADER_LEN + 4 + MAX_PAYLOAD_LEN + 2];
+    uint8_t payload_start = FRAME_HEADER_LEN;
+    data_to_send[0] = FRAME_SYNC_1;
+    data_to_send[1] = FRAME_SYNC_2;
+    data_to_send[2] = PROTOCOL_VERSION;
+    data_to_send[3] = packet->PacketKind;
+    data_to_send[4] = packet->PacketId;
+    if (this->send_timestamps) {
+      uint32_t now = micros();
+      data_to_send[3] |= FLAG_MICROS;
+      memcpy(&data_to_send[payload_start], &now, sizeof(now));
+      payload_start += sizeof(now);
     }
-    data_to_send[31] = 0x17; // Fail safe
+    data_to_send[5] = payload_start - FRAME_HEADER_LEN + packet->Length;
+    for (int i = 0; i < packet->Length; i++) {
+      data_to_send[payload_start + i] = packet->RawData[i];
+    }
+    uint8_t crc_start = payload_start + packet->Length;
+    // The sync bytes aren't part of the checksum
+    uint16_t crc = this->crc16(&data_to_send[2], crc_start - 2);
+    data_to_send[crc_start] = crc & 0xff;
+    data_to_send[crc_start + 1] = crc >> 8;
 
-    Serial.write(data_to_send, 32); // 32 seems to be the magic number for outputting it all in one go
-    
+    Serial.write(data_to_send, crc_start + 2);
   }
 
-  // Wipes data in buffer and sets it to given, also ends early if possible
+  // Wipes data in buffer and sets it to given, anything past MAX_PAYLOAD_LEN is dropped
   // Takes pointed to first element and the size of the array
   void set_data(Packet* packet, uint8_t* data, uint8_t data_size) {
+    if (data_size > MAX_PAYLOAD_LEN) {
+      data_size = MAX_PAYLOAD_LEN;
+    }
     for (int i = 0; i < data_size; i++) {
       packet->RawData[i] = data[i];
     }
-    packet->RawData[data_size] = 0x17;
+    packet->Length = data_size;
   }
 
   void set_data(Packet* packet, char* data, uint8_t data_size) {
-    for (int i = 0; i < data_size; i++) {
-      packet->RawData[i] = data[i];
-    }
-    packet->RawData[data_size] = 0x17;
+    this->set_data(packet, (uint8_t*) data, data_size);
   }
 
-  // Inserts the int16_t as a byte into the provided array
-  void convert_u16(int16_t in, uint8_t data[2]) {
-    data[0] = in & 0xff;
-    data[1] = (in >> 8);
-  }
 
 
   Packet create_packet(uint8_t packet_kind, uint8_t packet_id){
     struct Packet packet;
     packet.PacketKind = packet_kind;
     packet.PacketId = packet_id;
-    packet.RawData[32] = {0}; 
+    packet.Length = 0;
     return packet;
   }
 
@@ -75,12 +119,17 @@ class PacketHandler {
     }
   }
 
+  // Floats are sent as their raw little-endian bytes, no precision is lost
   void send(float* data, int id) {
-    // Really can't be bothered to figure out how floats work in binary tbh.
-    Packet pack = this->create_packet(5, id);
-    char str[29]; 
-    dtostrf(*data, 2, 5, str);
-    this->set_data(&pack, str, strlen(str));
+    Packet pack = this->create_packet(6, id);
+    this->set_data(&pack, (uint8_t*) data, sizeof(float));
+    this->send_packet(&pack);
+  }
+
+  // double is only 4 bytes on AVR boards, so use the right packet kind for the board
+  void send(double* data, int id) {
+    Packet pack = this->create_packet(sizeof(double) == 8 ? 7 : 6, id);
+    this->set_data(&pack, (uint8_t*) data, sizeof(double));
     this->send_packet(&pack);
   }
 
@@ -90,29 +139,136 @@ class PacketHandler {
     this->send_packet(&pack);
   }
 
-  void send(int16_t* data, int id) {
-    Packet pack;
-    uint8_t data_to_send[2];
-    if (*data < 0) {
-      pack = this->create_packet(3,id);
-    } else {
-      pack = this->create_packet(2, id);
+  // Sends raw bytes, e.g. a packed struct. The GUI can be given the struct layout to read it
+  void send(uint8_t* data, uint8_t data_size, int id) {
+    Packet pack = this->create_packet(4, id);
+    this->set_data(&pack, data, data_size);
+    this->send_packet(&pack);
+  }
+
+  // Integers are sent as little-endian two's complement, the packet kind gives the width and
+  // whether the integer is signed
+  void send_integer(uint8_t packet_kind, void* data, uint8_t data_size, int id) {
+    Packet pack = this->create_packet(packet_kind, id);
+    this->set_data(&pack, (uint8_t*) data, data_size);
+    this->send_packet(&pack);
+  }
+
+  void send(uint8_t* data, int id) { this->send_integer(8, data, sizeof(*data), id); }
+  void send(int8_t* data, int id) { this->send_integer(9, data, sizeof(*data), id); }
+  void send(uint16_t* data, int id) { this->send_integer(10, data, sizeof(*data), id); }
+  void send(int16_t* data, int id) { this->send_integer(11, data, sizeof(*data), id); }
+  void send(uint32_t* data, int id) { this->send_integer(12, data, sizeof(*data), id); }
+  void send(int32_t* data, int id) { this->send_integer(13, data, sizeof(*data), id); }
+  void send(uint64_t* data, int id) { this->send_integer(14, data, sizeof(*data), id); }
+  void send(int64_t* data, int id) { this->send_integer(15, data, sizeof(*data), id); }
+
+  // Vector packets carry several values that were taken at the same time under one id. Create
+  // the packet with create_packet(16, id), add each value with add_field then use send_packet
+  void add_field(Packet* packet, uint8_t field_kind, void* data, uint8_t data_size) {
+    if (packet->Length + 1 + data_size > MAX_PAYLOAD_LEN) {
+      return; // Doesn't fit, better to drop it than send half a value
     }
-    this->convert_u16(*data, data_to_send);
-    this->set_data(&pack, data_to_send, sizeof(data_to_send) / sizeof(data_to_send[0]));
+    packet->RawData[packet->Length] = field_kind;
+    memcpy(&packet->RawData[packet->Length + 1], data, data_size);
+    packet->Length += 1 + data_size;
+  }
+
+  void add_field(Packet* packet, int16_t value) { this->add_field(packet, 11, &value, sizeof(value)); }
+  void add_field(Packet* packet, int32_t value) { this->add_field(packet, 13, &value, sizeof(value)); }
+  void add_field(Packet* packet, float value) { this->add_field(packet, 6, &value, sizeof(value)); }
+
+  // Handshake, see src/device.rs in the GUI. The GUI sends a hello frame when it connects, reply
+  // with send_descriptor and a send_channel for every id the sketch uses
+  void send_descriptor(const char* name, const char* firmware_version) {
+    Packet pack = this->create_packet(17, 0);
+    pack.RawData[0] = PROTOCOL_VERSION;
+    pack.Length = 1;
+    this->add_string(&pack, name);
+    this->add_string(&pack, firmware_version);
+    this->send_packet(&pack);
+  }
+
+  // kind is the packet kind the channel is sent as, use NAN for min & max if there's no range
+  void send_channel(uint8_t id, uint8_t kind, float min, float max, const char* name, const char* unit) {
+    Packet pack = this->create_packet(18, id);
+    pack.RawData[0] = id;
+    pack.RawData[1] = kind;
+    memcpy(&pack.RawData[2], &min, sizeof(min));
+    memcpy(&pack.RawData[6], &max, sizeof(max));
+    pack.Length = 10;
+    this->add_string(&pack, name);
+    this->add_string(&pack, unit);
+    this->send_packet(&pack);
+  }
+
+  // Names a packet id and gives it a unit, the GUI shows values as raw * scale + offset. Can be
+  // sent at any time, without the handshake
+  void send_metadata(uint8_t id, float scale, float offset, const char* name, const char* unit) {
+    Packet pack = this->create_packet(19, id);
+    memcpy(&pack.RawData[0], &scale, sizeof(scale));
+    memcpy(&pack.RawData[4], &offset, sizeof(offset));
+    pack.Length = 8;
+    this->add_string(&pack, name);
+    this->add_string(&pack, unit);
     this->send_packet(&pack);
   }
+
+  // Strings are prefixed by their length, cut short if they don't fit
+  void add_string(Packet* packet, const char* text) {
+    uint8_t length = strlen(text);
+    if (packet->Length + 1 + length > MAX_PAYLOAD_LEN) {
+      length = MAX_PAYLOAD_LEN - packet->Length - 1;
+    }
+    packet->RawData[packet->Length] = length;
+    memcpy(&packet->RawData[packet->Length + 1], text, length);
+    packet->Length += 1 + length;
+  }
+
+  // Returns true once the GUI has sent a hello. Only the start of the frame is checked, the
+  // rest of it is ignored
+  bool poll_hello() {
+    while (Serial.available() > 0) {
+      uint8_t received = Serial.read();
+      switch (this->hello_matched) {
+        case 0: this->hello_matched = received == FRAME_SYNC_1 ? 1 : 0; break;
+        case 1: this->hello_matched = received == FRAME_SYNC_2 ? 2 : (received == FRAME_SYNC_1 ? 1 : 0); break;
+        case 2: this->hello_matched = 3; break; // Protocol version
+        case 3:
+          this->hello_matched = 0;
+          if (received == 17) {
+            return true;
+          }
+          break;
+      }
+    }
+    return false;
+  }
 };
 
 PacketHandler* packet_handler = new PacketHandler();
 Packet pack;
+
+// Tells the GUI what this sketch sends
+void announce() {
+  packet_handler->send_descriptor("Test Sketch", "1.0.0");
+  packet_handler->send_channel(0, 1, NAN, NAN, "Status", "");
+  packet_handler->send_channel(1, 6, -1.0, 1.0, "Sine", "");
+  packet_handler->send_channel(2, 11, -5.0, 5.0, "Square", "");
+  packet_handler->send_metadata(2, 0.01, 0.0, "Square", "V"); // Sent in hundredths of a volt
+}
+
 void setup() {
   Serial.begin(9600);
   pinMode(13, OUTPUT);
+  announce();
   packet_handler->send("Connected!", 0);
 }
 
 void loop() {
+  if (packet_handler->poll_hello()) {
+    announce();
+  }
   //packet_handler->send_packet(pack);
   int16_t t = -5325;
   int16_t t_2 = 500;